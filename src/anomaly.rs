@@ -0,0 +1,284 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Serialize;
+
+use crate::config::{Edition, GeoliteConfig};
+use crate::db;
+use crate::errors::GLErr;
+use crate::inspect::open;
+
+/// How one figure moved between the live database and a new build.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    /// Table name or country ISO code.
+    pub name: String,
+    pub old: u64,
+    pub new: u64,
+    /// `(new - old) / old`, `None` when `old` is 0.
+    pub change: Option<f64>,
+    /// Whether the change is beyond the configured threshold.
+    pub anomalous: bool,
+}
+
+impl Change {
+    fn new(name: &str, old: u64, new: u64, max_change: f64) -> Change {
+        let change = (old > 0).then(|| (new as f64 - old as f64) / old as f64);
+        Change {
+            name: name.to_string(),
+            old,
+            new,
+            change,
+            anomalous: change.map_or(new > 0, |c| c.abs() > max_change),
+        }
+    }
+
+    fn describe(&self, what: &str) -> String {
+        match self.change {
+            Some(c) => format!(
+                "{} {}: {} -> {} ({:+.1}%)",
+                what,
+                self.name,
+                self.old,
+                self.new,
+                c * 100.0
+            ),
+            None => format!("{} {}: {} -> {}", what, self.name, self.old, self.new),
+        }
+    }
+}
+
+/// What moved between the live database and a new build. Tables and
+/// countries that only one of them has are left out, as are countries with
+/// fewer than `min_country_networks` networks in both.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Report {
+    /// Row counts of every table.
+    pub tables: Vec<Change>,
+    /// Blocks per country, IPv4 and IPv6 together, for the countries that
+    /// changed.
+    pub networks_per_country: Vec<Change>,
+    /// IPv4 addresses per country, for the countries that changed.
+    pub ipv4_per_country: Vec<Change>,
+}
+
+impl Report {
+    /// The changes beyond their thresholds, one line each.
+    pub fn anomalies(&self) -> Vec<String> {
+        let tables = self.tables.iter().map(|c| (c, "rows of"));
+        let networks = self.networks_per_country.iter().map(|c| (c, "networks of"));
+        let ipv4 = self
+            .ipv4_per_country
+            .iter()
+            .map(|c| (c, "IPv4 addresses of"));
+        tables
+            .chain(networks)
+            .chain(ipv4)
+            .filter(|(c, _)| c.anomalous)
+            .map(|(c, what)| c.describe(what))
+            .collect()
+    }
+}
+
+// Row counts by table, and networks and IPv4 addresses by country.
+struct Stats {
+    tables: BTreeMap<&'static str, u64>,
+    countries: BTreeMap<String, (u64, u64)>,
+}
+
+/// Compares the database at `new` with the one at `live`.
+pub fn compare(config: &GeoliteConfig, live: &Path, new: &Path) -> Result<Report, GLErr> {
+    let live = open(live)?;
+    let new = open(new)?;
+    let new_editions = db::built_editions(&new)?;
+    let editions: Vec<Edition> = db::built_editions(&live)?
+        .into_iter()
+        .filter(|e| new_editions.contains(e))
+        .collect();
+    // City blocks are finer grained, so both sides have to use the same ones
+    let blocks = if editions.contains(&Edition::Country) {
+        Some(Edition::Country)
+    } else if editions.contains(&Edition::City) {
+        Some(Edition::City)
+    } else {
+        None
+    };
+    let (old, new) = (
+        stats(&live, &editions, blocks)?,
+        stats(&new, &editions, blocks)?,
+    );
+
+    let mut report = Report::default();
+    for (table, rows) in new.tables.iter() {
+        let old_rows = old.tables.get(table).copied().unwrap_or_default();
+        report.tables.push(Change::new(
+            table,
+            old_rows,
+            *rows,
+            config.max_table_change(),
+        ));
+    }
+    let mut countries: Vec<&String> = old.countries.keys().chain(new.countries.keys()).collect();
+    countries.sort();
+    countries.dedup();
+    for country in countries {
+        let (old_networks, old_ipv4) = old.countries.get(country).copied().unwrap_or_default();
+        let (new_networks, new_ipv4) = new.countries.get(country).copied().unwrap_or_default();
+        if old_networks.max(new_networks) < config.min_country_networks() {
+            continue;
+        }
+        if old_networks != new_networks {
+            report.networks_per_country.push(Change::new(
+                country,
+                old_networks,
+                new_networks,
+                config.max_country_change(),
+            ));
+        }
+        if old_ipv4 != new_ipv4 {
+            report.ipv4_per_country.push(Change::new(
+                country,
+                old_ipv4,
+                new_ipv4,
+                config.max_country_change(),
+            ));
+        }
+    }
+    Ok(report)
+}
+
+fn stats(
+    conn: &rusqlite::Connection,
+    editions: &[Edition],
+    blocks: Option<Edition>,
+) -> Result<Stats, GLErr> {
+    let mut tables = BTreeMap::new();
+    for table in editions.iter().flat_map(|e| e.tables()) {
+        let rows = conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |r| r.get(0))?;
+        tables.insert(*table, rows);
+    }
+    let mut countries = BTreeMap::<String, (u64, u64)>::new();
+    let (locations, v4, v6) = match blocks {
+        Some(Edition::Country) => ("countries", "countries4", "countries6"),
+        Some(Edition::City) => ("cities", "cities4", "cities6"),
+        _ => return Ok(Stats { tables, countries }),
+    };
+    for (table, addresses) in [(v4, "SUM(b.network_end - b.network_start + 1)"), (v6, "0")] {
+        // Blocks without a geoname_id are counted for the registered country
+        let mut stmt = conn.prepare(&format!(
+            "SELECT COALESCE(NULLIF(l.country_iso_code, ''), '(none)'), COUNT(*), {}
+            FROM {} b LEFT JOIN {} l
+            ON l.geoname_id = COALESCE(b.geoname_id, b.registered_country_geoname_id)
+            GROUP BY 1",
+            addresses, table, locations
+        ))?;
+        let mut rows = stmt.query([])?;
+        while let Some(r) = rows.next()? {
+            let entry = countries.entry(r.get(0)?).or_default();
+            entry.0 += r.get::<_, u64>(1)?;
+            entry.1 += r.get::<_, u64>(2)?;
+        }
+    }
+    Ok(Stats { tables, countries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DE: i64 = 2921044;
+    const US: i64 = 6252001;
+
+    // A Country database with `de` and `us` /24 IPv4 blocks and one IPv6
+    // block each.
+    fn build(dir: &Path, name: &str, de: u32, us: u32) -> std::path::PathBuf {
+        let path = dir.join(name);
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        db::build_tables(&conn, &[Edition::Country]).unwrap();
+        for (id, iso) in [(DE, "DE"), (US, "US")] {
+            conn.execute(
+                "INSERT INTO countries(geoname_id, country_iso_code) VALUES (?1, ?2)",
+                (id, iso),
+            )
+            .unwrap();
+        }
+        let blocks = (0..de)
+            .map(|i| (DE, 1, i))
+            .chain((0..us).map(|i| (US, 2, i)));
+        for (id, a, b) in blocks {
+            let start = (a << 24 | b << 8) as i64;
+            conn.execute(
+                "INSERT INTO countries4(network, geoname_id, network_start, network_end)
+                VALUES (?1, ?2, ?3, ?4)",
+                (format!("{}.0.{}.0/24", a, b), id, start, start + 255),
+            )
+            .unwrap();
+        }
+        for (id, net) in [(DE, "2001:200::/32"), (US, "2001:201::/32")] {
+            conn.execute(
+                "INSERT INTO countries6(network, geoname_id) VALUES (?1, ?2)",
+                (net, id),
+            )
+            .unwrap();
+        }
+        path
+    }
+
+    fn change(name: &str, old: u64, new: u64, anomalous: bool) -> Change {
+        Change {
+            name: name.to_string(),
+            old,
+            new,
+            change: Some((new as f64 - old as f64) / old as f64),
+            anomalous,
+        }
+    }
+
+    #[test]
+    fn shrunk_table_and_country_are_anomalies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let live = build(dir, "live.db", 4, 4);
+        let new = build(dir, "new.db", 4, 2);
+        let config = GeoliteConfig::builder()
+            .db_dir(dir)
+            .min_country_networks(3)
+            .build()
+            .unwrap();
+
+        let report = compare(&config, &live, &new).unwrap();
+        assert_eq!(
+            report,
+            Report {
+                tables: vec![
+                    change("countries", 2, 2, false),
+                    change("countries4", 8, 6, true),
+                    change("countries6", 2, 2, false),
+                ],
+                networks_per_country: vec![change("US", 5, 3, true)],
+                ipv4_per_country: vec![change("US", 1024, 512, true)],
+            }
+        );
+        assert_eq!(
+            report.anomalies(),
+            vec![
+                "rows of countries4: 8 -> 6 (-25.0%)",
+                "networks of US: 5 -> 3 (-40.0%)",
+                "IPv4 addresses of US: 1024 -> 512 (-50.0%)",
+            ]
+        );
+
+        // With fewer networks than that a country is not compared at all
+        let config = GeoliteConfig::builder()
+            .db_dir(dir)
+            .min_country_networks(6)
+            .build()
+            .unwrap();
+        let report = compare(&config, &live, &new).unwrap();
+        assert!(report.networks_per_country.is_empty());
+        assert!(report.ipv4_per_country.is_empty());
+        assert_eq!(
+            report.anomalies(),
+            vec!["rows of countries4: 8 -> 6 (-25.0%)"]
+        );
+    }
+}
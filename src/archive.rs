@@ -0,0 +1,380 @@
+use std::{
+    cell::Cell,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc;
+
+use crate::config::{Edition, GeoliteConfig};
+use crate::errors::GLErr;
+
+/// What a zip archive may hold before it is refused.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Limits {
+    /// Uncompressed bytes, declared by the whole archive or read from any
+    /// one entry.
+    max_size: u64,
+    max_entries: usize,
+}
+
+/// A CSV record deserialized, or not, and the line it starts on.
+pub(crate) struct Record<T> {
+    pub(crate) line: u64,
+    pub(crate) value: Result<T, csv::Error>,
+}
+
+/// Where an edition's CSVs are read from: a GeoLite2 zip archive, streamed
+/// without extracting it, or a directory one was extracted to. Files are
+/// found by name wherever they sit inside.
+#[derive(Debug, Clone)]
+pub(crate) enum CsvSource {
+    Zip(PathBuf, Limits),
+    Dir(PathBuf),
+}
+
+impl CsvSource {
+    pub(crate) fn new(path: &Path, config: &GeoliteConfig) -> CsvSource {
+        if path.is_dir() {
+            CsvSource::Dir(path.to_path_buf())
+        } else {
+            let limits = Limits {
+                max_size: config.max_archive_size(),
+                max_entries: config.max_archive_entries(),
+            };
+            CsvSource::Zip(path.to_path_buf(), limits)
+        }
+    }
+
+    /// The edition the CSVs belong to, if there are any.
+    pub(crate) fn edition(&self) -> Result<Option<Edition>, GLErr> {
+        match self {
+            CsvSource::Zip(p, limits) => {
+                let z = open_zip(p, limits)?;
+                let edition = z.file_names().find_map(edition_of);
+                Ok(edition)
+            }
+            CsvSource::Dir(p) => Ok(csv_dir(p).and_then(|d| dir_edition(&d))),
+        }
+    }
+
+    /// Where the CSV called `name` is, for messages: its path, or the zip's
+    /// path and the entry's name inside it.
+    pub(crate) fn location(&self, name: &str) -> String {
+        match self {
+            CsvSource::Zip(p, limits) => {
+                let entry = open_zip(p, limits).ok().and_then(|z| {
+                    z.file_names()
+                        .find(|n| base_name(n) == name)
+                        .map(String::from)
+                });
+                format!("{}:{}", p.display(), entry.as_deref().unwrap_or(name))
+            }
+            CsvSource::Dir(p) => csv_dir(p)
+                .unwrap_or_else(|| p.clone())
+                .join(name)
+                .display()
+                .to_string(),
+        }
+    }
+
+    /// Deserializes the rows of the CSV called `name` on a blocking thread
+    /// and sends them over the returned channel. A row that cannot be read
+    /// is sent as a `Record` holding the error; anything that stops the
+    /// whole file from being read is sent as an `Err` and ends it.
+    pub(crate) fn rows<T: DeserializeOwned + Send + 'static>(
+        &self,
+        name: &'static str,
+    ) -> mpsc::Receiver<Result<Record<T>, GLErr>> {
+        let (tx, rx) = mpsc::channel(1000);
+        let source = self.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = source.send_rows(name, &tx) {
+                let _ = tx.blocking_send(Err(e));
+            }
+        });
+        rx
+    }
+
+    fn send_rows<T: DeserializeOwned>(
+        &self,
+        name: &str,
+        tx: &mpsc::Sender<Result<Record<T>, GLErr>>,
+    ) -> Result<(), GLErr> {
+        let not_found = || GLErr::CsvNotFound(name.to_string());
+        match self {
+            CsvSource::Zip(p, limits) => {
+                let mut z = open_zip(p, limits)?;
+                let entry = z
+                    .file_names()
+                    .find(|n| base_name(n) == name)
+                    .map(String::from)
+                    .ok_or_else(not_found)?;
+                let over = Rc::new(Cell::new(false));
+                let reader = Limited {
+                    inner: z.by_name(&entry)?,
+                    left: limits.max_size,
+                    over: Rc::clone(&over),
+                };
+                send(csv::Reader::from_reader(reader), tx, |e| {
+                    if over.get() {
+                        return GLErr::UnsafeArchive(format!(
+                            "{} in {:?} is larger than {} bytes",
+                            entry, p, limits.max_size
+                        ));
+                    }
+                    e.into()
+                });
+            }
+            CsvSource::Dir(p) => {
+                let path = csv_dir(p).ok_or_else(not_found)?.join(name);
+                if !path.is_file() {
+                    return Err(not_found());
+                }
+                send(csv::Reader::from_path(path)?, tx, GLErr::from);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Stops early once the receiver is gone.
+fn send<R: Read, T: DeserializeOwned, F: Fn(csv::Error) -> GLErr>(
+    mut reader: csv::Reader<R>,
+    tx: &mpsc::Sender<Result<Record<T>, GLErr>>,
+    map_err: F,
+) {
+    let headers = match reader.headers() {
+        Ok(h) => h.clone(),
+        Err(e) => {
+            let _ = tx.blocking_send(Err(map_err(e)));
+            return;
+        }
+    };
+    let mut record = csv::StringRecord::new();
+    loop {
+        let sent = match reader.read_record(&mut record) {
+            Ok(false) => break,
+            Ok(true) => tx.blocking_send(Ok(Record {
+                line: record.position().map_or(0, |p| p.line()),
+                value: record.deserialize(Some(&headers)),
+            })),
+            // The reader carries on with the next record after these
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    csv::ErrorKind::Utf8 { .. } | csv::ErrorKind::UnequalLengths { .. }
+                ) =>
+            {
+                tx.blocking_send(Ok(Record {
+                    line: e.position().map_or(0, |p| p.line()),
+                    value: Err(e),
+                }))
+            }
+            Err(e) => {
+                let _ = tx.blocking_send(Err(map_err(e)));
+                break;
+            }
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+}
+
+// Opens an archive after checking its entry count, that every entry name
+// stays inside the archive, and the uncompressed size the entries declare.
+fn open_zip(path: &Path, limits: &Limits) -> Result<zip::ZipArchive<File>, GLErr> {
+    let mut z = zip::ZipArchive::new(File::open(path)?)?;
+    if z.len() > limits.max_entries {
+        return Err(GLErr::UnsafeArchive(format!(
+            "{:?} has {} entries, more than {}",
+            path,
+            z.len(),
+            limits.max_entries
+        )));
+    }
+    let mut size = 0u64;
+    for i in 0..z.len() {
+        let entry = z.by_index_raw(i)?;
+        if entry.enclosed_name().is_none() {
+            return Err(GLErr::UnsafeArchive(format!(
+                "{:?} has an entry outside the archive: {}",
+                path,
+                entry.name()
+            )));
+        }
+        size = size.saturating_add(entry.size());
+    }
+    if size > limits.max_size {
+        return Err(GLErr::UnsafeArchive(format!(
+            "{:?} holds {} bytes uncompressed, more than {}",
+            path, size, limits.max_size
+        )));
+    }
+    Ok(z)
+}
+
+// Fails reads past `left` bytes, whatever the entry declared.
+struct Limited<R> {
+    inner: R,
+    left: u64,
+    over: Rc<Cell<bool>>,
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n as u64 > self.left {
+            self.over.set(true);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "archive entry exceeds the size limit",
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+fn base_name(name: &str) -> &str {
+    name.rsplit('/').next().unwrap_or(name)
+}
+
+// "GeoLite2-City-CSV" archives hold "GeoLite2-City-*.csv" files.
+fn edition_of(name: &str) -> Option<Edition> {
+    let name = base_name(name);
+    if !name.ends_with(".csv") {
+        return None;
+    }
+    Edition::ALL
+        .into_iter()
+        .find(|e| name.starts_with(e.id().trim_end_matches("CSV")))
+}
+
+fn dir_edition(dir: &Path) -> Option<Edition> {
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .find_map(|e| edition_of(&e.file_name().to_string_lossy()))
+}
+
+// `dir` itself or, as laid out in MaxMind's archives, the
+// `GeoLite2-*-CSV_<date>` directory inside it.
+fn csv_dir(dir: &Path) -> Option<PathBuf> {
+    if dir_edition(dir).is_some() {
+        return Some(dir.to_path_buf());
+    }
+    std::fs::read_dir(dir)
+        .ok()?
+        .flatten()
+        .map(|e| e.path())
+        .find(|p| p.is_dir() && dir_edition(p).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_size: 1000,
+        max_entries: 3,
+    };
+
+    // Writes a deflated zip of `entries` to a temp dir, which is removed
+    // when dropped.
+    fn zip(entries: &[(&str, &[u8])]) -> (tempfile::TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("archive.zip");
+        let mut w = zip::ZipWriter::new(File::create(&path).unwrap());
+        let options =
+            zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+        for (entry, contents) in entries {
+            w.start_file(*entry, options).unwrap();
+            w.write_all(contents).unwrap();
+        }
+        w.finish().unwrap();
+        (dir, path)
+    }
+
+    fn is_unsafe<T>(r: Result<T, GLErr>) -> bool {
+        matches!(r, Err(GLErr::UnsafeArchive(_)))
+    }
+
+    #[test]
+    fn entries_outside_the_archive_are_refused() {
+        let csv: &[u8] = b"a,b\n1,2\n";
+        for entry in ["../x.csv", "a/../../x.csv", "/x.csv"] {
+            let (_dir, path) = zip(&[("GeoLite2-ASN-CSV/ok.csv", csv), (entry, csv)]);
+            assert!(is_unsafe(open_zip(&path, &LIMITS)), "{} was opened", entry);
+        }
+        let (_dir, path) = zip(&[("GeoLite2-ASN-CSV/sub/../x.csv", csv)]);
+        assert!(open_zip(&path, &LIMITS).is_ok());
+    }
+
+    #[test]
+    fn too_many_entries_are_refused() {
+        let csv: &[u8] = b"a\n";
+        let (_dir, path) = zip(&[("1.csv", csv), ("2.csv", csv), ("3.csv", csv)]);
+        assert!(open_zip(&path, &LIMITS).is_ok());
+        let (_dir, path) = zip(&[
+            ("1.csv", csv),
+            ("2.csv", csv),
+            ("3.csv", csv),
+            ("4.csv", csv),
+        ]);
+        assert!(is_unsafe(open_zip(&path, &LIMITS)));
+    }
+
+    #[test]
+    fn declared_size_over_the_limit_is_refused() {
+        let big = vec![b'1'; 600];
+        let (_dir, path) = zip(&[("1.csv", &big[..]), ("2.csv", &big[..300])]);
+        assert!(open_zip(&path, &LIMITS).is_ok());
+        let (_dir, path) = zip(&[("1.csv", &big[..]), ("2.csv", &big[..])]);
+        assert!(is_unsafe(open_zip(&path, &LIMITS)));
+    }
+
+    #[tokio::test]
+    async fn real_size_over_the_limit_is_refused() {
+        let mut csv = b"a,b\n".to_vec();
+        for _ in 0..1000 {
+            csv.extend_from_slice(b"1,2\n");
+        }
+        let (_dir, path) = zip(&[("GeoLite2-ASN-Blocks-IPv4.csv", &csv)]);
+
+        // Make the entry claim 100 bytes in its local and central headers
+        let mut bytes = std::fs::read(&path).unwrap();
+        for (signature, offset) in [(b"PK\x03\x04", 22), (b"PK\x01\x02", 24)] {
+            let at = bytes.windows(4).position(|w| w == signature).unwrap();
+            bytes[at + offset..at + offset + 4].copy_from_slice(&100u32.to_le_bytes());
+        }
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(
+            open_zip(&path, &LIMITS)
+                .unwrap()
+                .by_index_raw(0)
+                .unwrap()
+                .size(),
+            100
+        );
+
+        let source = CsvSource::Zip(path, LIMITS);
+        let mut rx = source.rows::<Vec<String>>("GeoLite2-ASN-Blocks-IPv4.csv");
+        let mut rows = 0;
+        let result = loop {
+            match rx.recv().await {
+                Some(Ok(_)) => rows += 1,
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            }
+        };
+        assert!(is_unsafe(result));
+        assert!(rows < 250);
+    }
+}
@@ -0,0 +1,820 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+use crate::db;
+use crate::download::{Progress, ProgressFn};
+use crate::errors::GLErr;
+use crate::schedule::Schedule;
+
+const DEFAULT_BASE_URL: &str = "https://download.maxmind.com";
+// The City archive holds about 400MB of CSVs in 14 files.
+const DEFAULT_MAX_ARCHIVE_SIZE: u64 = 2 << 30;
+const DEFAULT_MAX_ARCHIVE_ENTRIES: usize = 64;
+// A weekly GeoLite2 release moves table sizes by a few percent.
+const DEFAULT_MAX_TABLE_CHANGE: f64 = 0.1;
+const DEFAULT_MAX_COUNTRY_CHANGE: f64 = 0.25;
+// Below this, one reassigned block is already a big relative change.
+const DEFAULT_MIN_COUNTRY_NETWORKS: u64 = 100;
+
+/// The GeoLite2 CSV databases MaxMind publishes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Edition {
+    Country,
+    City,
+    Asn,
+}
+
+impl Edition {
+    pub const ALL: [Edition; 3] = [Edition::Country, Edition::City, Edition::Asn];
+
+    /// MaxMind's `edition_id`.
+    pub fn id(&self) -> &'static str {
+        match self {
+            Edition::Country => "GeoLite2-Country-CSV",
+            Edition::City => "GeoLite2-City-CSV",
+            Edition::Asn => "GeoLite2-ASN-CSV",
+        }
+    }
+
+    // Name of the archive and extraction directory under `dbfiles`.
+    pub(crate) fn dir_name(&self) -> &'static str {
+        match self {
+            Edition::Country => "countries",
+            Edition::City => "cities",
+            Edition::Asn => "asn",
+        }
+    }
+
+    /// Tables holding the edition's data.
+    pub fn tables(&self) -> &'static [&'static str] {
+        match self {
+            Edition::Country => &["countries", "countries4", "countries6"],
+            Edition::City => &["cities", "cities4", "cities6"],
+            Edition::Asn => &["asn4", "asn6"],
+        }
+    }
+}
+
+impl std::fmt::Display for Edition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Edition::Country => write!(f, "Country"),
+            Edition::City => write!(f, "City"),
+            Edition::Asn => write!(f, "ASN"),
+        }
+    }
+}
+
+/// Accepts `country`, `city` or `asn` in any case.
+impl FromStr for Edition {
+    type Err = GLErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "country" => Ok(Edition::Country),
+            "city" => Ok(Edition::City),
+            "asn" => Ok(Edition::Asn),
+            _ => Err(GLErr::InvalidConfig(format!("unknown edition {}", s))),
+        }
+    }
+}
+
+impl TryFrom<String> for Edition {
+    type Error = GLErr;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// What a build does with CSV rows that cannot be parsed or inserted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ErrorPolicy {
+    /// Fail on the first one.
+    #[default]
+    Abort,
+    /// Leave out and log up to this many per table, failing on the next.
+    Skip(usize),
+    /// Import the rest, then fail listing every one of them.
+    Collect,
+}
+
+/// Accepts `abort`, `skip:<max>` or `collect`.
+impl FromStr for ErrorPolicy {
+    type Err = GLErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || GLErr::InvalidConfig(format!("unknown error policy {}", s));
+        match s.trim().to_ascii_lowercase().as_str() {
+            "abort" => Ok(ErrorPolicy::Abort),
+            "collect" => Ok(ErrorPolicy::Collect),
+            p => {
+                let max = p.strip_prefix("skip:").ok_or_else(invalid)?;
+                Ok(ErrorPolicy::Skip(
+                    max.trim().parse().map_err(|_| invalid())?,
+                ))
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for ErrorPolicy {
+    type Error = GLErr;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// What a build does when it differs from the live database by more than
+/// the configured thresholds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum AnomalyAction {
+    /// Keep the live database.
+    Refuse,
+    /// Print the anomalies and swap the build in anyway.
+    #[default]
+    Warn,
+    /// Skip the comparison.
+    Off,
+}
+
+/// Accepts `refuse`, `warn` or `off`.
+impl FromStr for AnomalyAction {
+    type Err = GLErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "refuse" => Ok(AnomalyAction::Refuse),
+            "warn" => Ok(AnomalyAction::Warn),
+            "off" => Ok(AnomalyAction::Off),
+            _ => Err(GLErr::InvalidConfig(format!(
+                "unknown anomaly action {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for AnomalyAction {
+    type Error = GLErr;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// An address and the ISO code of the country a new build must resolve it
+/// to, written `8.8.8.8=US`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct GoldenIp {
+    pub ip: IpAddr,
+    pub country: String,
+}
+
+impl FromStr for GoldenIp {
+    type Err = GLErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || GLErr::InvalidConfig(format!("invalid golden IP {}", s));
+        let (ip, country) = s.split_once('=').ok_or_else(invalid)?;
+        let country = country.trim().to_ascii_uppercase();
+        if country.is_empty() {
+            return Err(invalid());
+        }
+        Ok(GoldenIp {
+            ip: ip.trim().parse().map_err(|_| invalid())?,
+            country,
+        })
+    }
+}
+
+impl TryFrom<String> for GoldenIp {
+    type Error = GLErr;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// Keeps license keys out of logs and error reports.
+fn redacted(key: &Option<String>) -> Option<&'static str> {
+    key.as_ref().map(|_| "<redacted>")
+}
+
+/// Everything the library needs to download, build and locate the database.
+/// Build one with `GeoliteConfig::builder()`, `from_env` or `from_toml_file`.
+#[derive(Clone)]
+pub struct GeoliteConfig {
+    db_dir: PathBuf,
+    license_key: Option<String>,
+    account_id: Option<String>,
+    editions: Vec<Edition>,
+    phone_file: Option<PathBuf>,
+    schedule: Schedule,
+    base_url: String,
+    max_archive_size: u64,
+    max_archive_entries: usize,
+    error_policy: ErrorPolicy,
+    min_rows: HashMap<String, u64>,
+    golden_ips: Vec<GoldenIp>,
+    anomaly_action: AnomalyAction,
+    max_table_change: f64,
+    max_country_change: f64,
+    min_country_networks: u64,
+    progress: Option<ProgressFn>,
+}
+
+impl std::fmt::Debug for GeoliteConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoliteConfig")
+            .field("db_dir", &self.db_dir)
+            .field("license_key", &redacted(&self.license_key))
+            .field("account_id", &self.account_id)
+            .field("editions", &self.editions)
+            .field("phone_file", &self.phone_file)
+            .field("schedule", &self.schedule)
+            .field("base_url", &self.base_url)
+            .field("max_archive_size", &self.max_archive_size)
+            .field("max_archive_entries", &self.max_archive_entries)
+            .field("error_policy", &self.error_policy)
+            .field("min_rows", &self.min_rows)
+            .field("golden_ips", &self.golden_ips)
+            .field("anomaly_action", &self.anomaly_action)
+            .field("max_table_change", &self.max_table_change)
+            .field("max_country_change", &self.max_country_change)
+            .field("min_country_networks", &self.min_country_networks)
+            .field("progress", &self.progress)
+            .finish()
+    }
+}
+
+impl GeoliteConfig {
+    pub fn builder() -> GeoliteConfigBuilder {
+        GeoliteConfigBuilder::default()
+    }
+
+    /// Reads `GL2_DBDIR`, `MAXMIND_KEY`, `MAXMIND_ACCOUNT_ID`, `GL2_EDITIONS`
+    /// (comma separated), `PHONE_JSON_FILE`, `GL2_SCHEDULE`, `GL2_BASE_URL`,
+    /// `GL2_MAX_ARCHIVE_SIZE`, `GL2_MAX_ARCHIVE_ENTRIES`, `GL2_ERROR_POLICY`,
+    /// `GL2_MIN_ROWS` (`table=rows`, comma separated), `GL2_GOLDEN_IPS`
+    /// (comma separated), `GL2_ANOMALY_ACTION`, `GL2_MAX_TABLE_CHANGE`,
+    /// `GL2_MAX_COUNTRY_CHANGE` and `GL2_MIN_COUNTRY_NETWORKS`.
+    pub fn from_env() -> Result<GeoliteConfig, GLErr> {
+        GeoliteConfigBuilder::default().env()?.build()
+    }
+
+    /// Reads a TOML file whose keys are the field names of the builder, e.g.
+    /// `db_dir = "/var/lib/geolite"` or `editions = ["Country", "ASN"]`.
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<GeoliteConfig, GLErr> {
+        GeoliteConfigBuilder::from_toml_file(path)?.build()
+    }
+
+    pub fn db_dir(&self) -> &Path {
+        &self.db_dir
+    }
+
+    /// Path of the live database inside `db_dir`.
+    pub fn db_path(&self) -> PathBuf {
+        self.db_dir.join("geolite2.db")
+    }
+
+    pub fn license_key(&self) -> Option<&str> {
+        self.license_key.as_deref()
+    }
+
+    pub fn account_id(&self) -> Option<&str> {
+        self.account_id.as_deref()
+    }
+
+    pub fn editions(&self) -> &[Edition] {
+        &self.editions
+    }
+
+    pub fn phone_file(&self) -> Option<&Path> {
+        self.phone_file.as_deref()
+    }
+
+    pub fn schedule(&self) -> &Schedule {
+        &self.schedule
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    /// Uncompressed bytes an archive may hold.
+    pub fn max_archive_size(&self) -> u64 {
+        self.max_archive_size
+    }
+
+    pub fn max_archive_entries(&self) -> usize {
+        self.max_archive_entries
+    }
+
+    pub fn error_policy(&self) -> ErrorPolicy {
+        self.error_policy
+    }
+
+    /// Rows `table` must hold for a build to be swapped in: 1 unless
+    /// configured.
+    pub fn min_rows(&self, table: &str) -> u64 {
+        self.min_rows.get(table).copied().unwrap_or(1)
+    }
+
+    pub fn golden_ips(&self) -> &[GoldenIp] {
+        &self.golden_ips
+    }
+
+    pub fn anomaly_action(&self) -> AnomalyAction {
+        self.anomaly_action
+    }
+
+    /// Largest change of a table's row count, as a fraction of the live
+    /// one, that is not an anomaly.
+    pub fn max_table_change(&self) -> f64 {
+        self.max_table_change
+    }
+
+    /// Like `max_table_change`, for the networks and IPv4 addresses of a
+    /// country.
+    pub fn max_country_change(&self) -> f64 {
+        self.max_country_change
+    }
+
+    /// Countries with fewer networks in both databases are not compared.
+    pub fn min_country_networks(&self) -> u64 {
+        self.min_country_networks
+    }
+
+    pub fn progress(&self) -> Option<&ProgressFn> {
+        self.progress.as_ref()
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoliteConfigBuilder {
+    db_dir: Option<PathBuf>,
+    license_key: Option<String>,
+    account_id: Option<String>,
+    editions: Option<Vec<Edition>>,
+    phone_file: Option<PathBuf>,
+    schedule: Option<Schedule>,
+    base_url: Option<String>,
+    max_archive_size: Option<u64>,
+    max_archive_entries: Option<usize>,
+    error_policy: Option<ErrorPolicy>,
+    min_rows: Option<HashMap<String, u64>>,
+    golden_ips: Option<Vec<GoldenIp>>,
+    anomaly_action: Option<AnomalyAction>,
+    max_table_change: Option<f64>,
+    max_country_change: Option<f64>,
+    min_country_networks: Option<u64>,
+    #[serde(skip)]
+    progress: Option<ProgressFn>,
+}
+
+impl std::fmt::Debug for GeoliteConfigBuilder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoliteConfigBuilder")
+            .field("db_dir", &self.db_dir)
+            .field("license_key", &redacted(&self.license_key))
+            .field("account_id", &self.account_id)
+            .field("editions", &self.editions)
+            .field("phone_file", &self.phone_file)
+            .field("schedule", &self.schedule)
+            .field("base_url", &self.base_url)
+            .field("max_archive_size", &self.max_archive_size)
+            .field("max_archive_entries", &self.max_archive_entries)
+            .field("error_policy", &self.error_policy)
+            .field("min_rows", &self.min_rows)
+            .field("golden_ips", &self.golden_ips)
+            .field("anomaly_action", &self.anomaly_action)
+            .field("max_table_change", &self.max_table_change)
+            .field("max_country_change", &self.max_country_change)
+            .field("min_country_networks", &self.min_country_networks)
+            .field("progress", &self.progress)
+            .finish()
+    }
+}
+
+impl GeoliteConfigBuilder {
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<GeoliteConfigBuilder, GLErr> {
+        let s = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&s)?)
+    }
+
+    /// Overrides fields with whichever of the `from_env` variables are set.
+    pub fn env(mut self) -> Result<GeoliteConfigBuilder, GLErr> {
+        if let Ok(v) = std::env::var("GL2_DBDIR") {
+            self.db_dir = Some(v.into());
+        }
+        if let Ok(v) = std::env::var("MAXMIND_KEY") {
+            self.license_key = Some(v);
+        }
+        if let Ok(v) = std::env::var("MAXMIND_ACCOUNT_ID") {
+            self.account_id = Some(v);
+        }
+        if let Ok(v) = std::env::var("GL2_EDITIONS") {
+            let editions = v
+                .split(',')
+                .map(|e| e.parse())
+                .collect::<Result<Vec<Edition>, GLErr>>()?;
+            self.editions = Some(editions);
+        }
+        if let Ok(v) = std::env::var("PHONE_JSON_FILE") {
+            self.phone_file = Some(v.into());
+        }
+        if let Ok(v) = std::env::var("GL2_SCHEDULE") {
+            self.schedule = Some(v.parse()?);
+        }
+        if let Ok(v) = std::env::var("GL2_BASE_URL") {
+            self.base_url = Some(v);
+        }
+        if let Ok(v) = std::env::var("GL2_MAX_ARCHIVE_SIZE") {
+            self.max_archive_size = Some(v.parse()?);
+        }
+        if let Ok(v) = std::env::var("GL2_MAX_ARCHIVE_ENTRIES") {
+            self.max_archive_entries = Some(v.parse()?);
+        }
+        if let Ok(v) = std::env::var("GL2_ERROR_POLICY") {
+            self.error_policy = Some(v.parse()?);
+        }
+        if let Ok(v) = std::env::var("GL2_MIN_ROWS") {
+            let mut min_rows = HashMap::new();
+            for pair in v.split(',') {
+                let (table, rows) = pair.split_once('=').ok_or_else(|| {
+                    GLErr::InvalidConfig(format!("invalid minimum row count {}", pair))
+                })?;
+                min_rows.insert(table.trim().to_string(), rows.trim().parse()?);
+            }
+            self.min_rows = Some(min_rows);
+        }
+        if let Ok(v) = std::env::var("GL2_GOLDEN_IPS") {
+            let ips = v
+                .split(',')
+                .map(|g| g.parse())
+                .collect::<Result<Vec<GoldenIp>, GLErr>>()?;
+            self.golden_ips = Some(ips);
+        }
+        if let Ok(v) = std::env::var("GL2_ANOMALY_ACTION") {
+            self.anomaly_action = Some(v.parse()?);
+        }
+        let fraction = |v: String| {
+            v.trim()
+                .parse::<f64>()
+                .map_err(|_| GLErr::InvalidConfig(format!("invalid fraction {}", v)))
+        };
+        if let Ok(v) = std::env::var("GL2_MAX_TABLE_CHANGE") {
+            self.max_table_change = Some(fraction(v)?);
+        }
+        if let Ok(v) = std::env::var("GL2_MAX_COUNTRY_CHANGE") {
+            self.max_country_change = Some(fraction(v)?);
+        }
+        if let Ok(v) = std::env::var("GL2_MIN_COUNTRY_NETWORKS") {
+            self.min_country_networks = Some(v.parse()?);
+        }
+        Ok(self)
+    }
+
+    pub fn db_dir<P: Into<PathBuf>>(mut self, dir: P) -> GeoliteConfigBuilder {
+        self.db_dir = Some(dir.into());
+        self
+    }
+
+    pub fn license_key<S: Into<String>>(mut self, key: S) -> GeoliteConfigBuilder {
+        self.license_key = Some(key.into());
+        self
+    }
+
+    pub fn account_id<S: Into<String>>(mut self, id: S) -> GeoliteConfigBuilder {
+        self.account_id = Some(id.into());
+        self
+    }
+
+    pub fn editions(mut self, editions: &[Edition]) -> GeoliteConfigBuilder {
+        self.editions = Some(editions.to_vec());
+        self
+    }
+
+    pub fn phone_file<P: Into<PathBuf>>(mut self, path: P) -> GeoliteConfigBuilder {
+        self.phone_file = Some(path.into());
+        self
+    }
+
+    pub fn schedule(mut self, schedule: Schedule) -> GeoliteConfigBuilder {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    pub fn base_url<S: Into<String>>(mut self, url: S) -> GeoliteConfigBuilder {
+        self.base_url = Some(url.into());
+        self
+    }
+
+    pub fn max_archive_size(mut self, bytes: u64) -> GeoliteConfigBuilder {
+        self.max_archive_size = Some(bytes);
+        self
+    }
+
+    pub fn max_archive_entries(mut self, entries: usize) -> GeoliteConfigBuilder {
+        self.max_archive_entries = Some(entries);
+        self
+    }
+
+    pub fn error_policy(mut self, policy: ErrorPolicy) -> GeoliteConfigBuilder {
+        self.error_policy = Some(policy);
+        self
+    }
+
+    /// Rows `table` must hold for a build to be swapped in.
+    pub fn min_rows<S: Into<String>>(mut self, table: S, rows: u64) -> GeoliteConfigBuilder {
+        self.min_rows
+            .get_or_insert_with(HashMap::new)
+            .insert(table.into(), rows);
+        self
+    }
+
+    /// Addresses a build must resolve to the given countries to be swapped
+    /// in.
+    pub fn golden_ips(mut self, ips: &[GoldenIp]) -> GeoliteConfigBuilder {
+        self.golden_ips = Some(ips.to_vec());
+        self
+    }
+
+    pub fn anomaly_action(mut self, action: AnomalyAction) -> GeoliteConfigBuilder {
+        self.anomaly_action = Some(action);
+        self
+    }
+
+    /// e.g. `0.1` to allow row counts to move by 10%.
+    pub fn max_table_change(mut self, fraction: f64) -> GeoliteConfigBuilder {
+        self.max_table_change = Some(fraction);
+        self
+    }
+
+    pub fn max_country_change(mut self, fraction: f64) -> GeoliteConfigBuilder {
+        self.max_country_change = Some(fraction);
+        self
+    }
+
+    pub fn min_country_networks(mut self, networks: u64) -> GeoliteConfigBuilder {
+        self.min_country_networks = Some(networks);
+        self
+    }
+
+    /// Called as archive downloads make progress.
+    pub fn on_progress<F: Fn(&Progress) + Send + Sync + 'static>(
+        mut self,
+        f: F,
+    ) -> GeoliteConfigBuilder {
+        self.progress = Some(ProgressFn::new(f));
+        self
+    }
+
+    pub fn build(self) -> Result<GeoliteConfig, GLErr> {
+        let db_dir = self
+            .db_dir
+            .ok_or_else(|| GLErr::InvalidConfig(String::from("db_dir is not set")))?;
+        let mut editions = Vec::<Edition>::new();
+        for e in self.editions.unwrap_or_else(|| Edition::ALL.to_vec()) {
+            if !editions.contains(&e) {
+                editions.push(e);
+            }
+        }
+        if editions.is_empty() {
+            return Err(GLErr::InvalidConfig(String::from("no editions selected")));
+        }
+        let min_rows = self.min_rows.unwrap_or_default();
+        if let Some(t) = min_rows.keys().find(|t| !db::TABLES.contains(&t.as_str())) {
+            return Err(GLErr::InvalidConfig(format!(
+                "unknown table {} in min_rows",
+                t
+            )));
+        }
+        let max_table_change = self.max_table_change.unwrap_or(DEFAULT_MAX_TABLE_CHANGE);
+        let max_country_change = self
+            .max_country_change
+            .unwrap_or(DEFAULT_MAX_COUNTRY_CHANGE);
+        // Also refuses NaN
+        if !(max_table_change >= 0.0 && max_country_change >= 0.0) {
+            return Err(GLErr::InvalidConfig(String::from(
+                "change thresholds must not be negative",
+            )));
+        }
+        Ok(GeoliteConfig {
+            db_dir,
+            license_key: self.license_key,
+            account_id: self.account_id,
+            editions,
+            phone_file: self.phone_file,
+            schedule: self.schedule.unwrap_or_default(),
+            base_url: self
+                .base_url
+                .unwrap_or_else(|| String::from(DEFAULT_BASE_URL))
+                .trim_end_matches('/')
+                .to_string(),
+            max_archive_size: self.max_archive_size.unwrap_or(DEFAULT_MAX_ARCHIVE_SIZE),
+            max_archive_entries: self
+                .max_archive_entries
+                .unwrap_or(DEFAULT_MAX_ARCHIVE_ENTRIES),
+            error_policy: self.error_policy.unwrap_or_default(),
+            min_rows,
+            golden_ips: self.golden_ips.unwrap_or_default(),
+            anomaly_action: self.anomaly_action.unwrap_or_default(),
+            max_table_change,
+            max_country_change,
+            min_country_networks: self
+                .min_country_networks
+                .unwrap_or(DEFAULT_MIN_COUNTRY_NETWORKS),
+            progress: self.progress,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn builder() -> GeoliteConfigBuilder {
+        GeoliteConfig::builder().db_dir("/var/lib/geolite")
+    }
+
+    fn invalid(r: Result<GeoliteConfig, GLErr>) -> String {
+        match r {
+            Err(GLErr::InvalidConfig(e)) => e,
+            r => panic!("expected an invalid config, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn editions_in_any_case() {
+        assert_eq!(" ASN ".parse::<Edition>().unwrap(), Edition::Asn);
+        assert_eq!("city".parse::<Edition>().unwrap(), Edition::City);
+        assert!("planet".parse::<Edition>().is_err());
+
+        let b: GeoliteConfigBuilder =
+            toml::from_str(r#"editions = ["country", "ASN", "City"]"#).unwrap();
+        assert_eq!(
+            b.db_dir("/x").build().unwrap().editions(),
+            &[Edition::Country, Edition::Asn, Edition::City]
+        );
+        assert!(toml::from_str::<GeoliteConfigBuilder>(r#"editions = ["planet"]"#).is_err());
+    }
+
+    #[test]
+    fn toml_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("geolite.toml");
+        std::fs::write(
+            &path,
+            r#"
+            db_dir = "/var/lib/geolite"
+            license_key = "KEY"
+            account_id = "42"
+            editions = ["country", "asn"]
+            schedule = "12h"
+            base_url = "http://mirror.local/"
+            max_archive_entries = 10
+            error_policy = "skip:5"
+            min_rows = { countries4 = 1000 }
+            golden_ips = ["8.8.8.8=us"]
+            anomaly_action = "refuse"
+            max_table_change = 0.5
+            "#,
+        )
+        .unwrap();
+        let config = GeoliteConfig::from_toml_file(&path).unwrap();
+        assert_eq!(config.db_dir(), Path::new("/var/lib/geolite"));
+        assert_eq!(config.license_key(), Some("KEY"));
+        assert_eq!(config.account_id(), Some("42"));
+        assert_eq!(config.editions(), &[Edition::Country, Edition::Asn]);
+        assert_eq!(
+            config.schedule(),
+            &Schedule::Interval(Duration::from_secs(12 * 3600))
+        );
+        assert_eq!(config.base_url(), "http://mirror.local");
+        assert_eq!(config.max_archive_entries(), 10);
+        assert_eq!(config.error_policy(), ErrorPolicy::Skip(5));
+        assert_eq!(
+            (config.min_rows("countries4"), config.min_rows("asn4")),
+            (1000, 1)
+        );
+        assert_eq!(config.golden_ips(), &["8.8.8.8=US".parse().unwrap()]);
+        assert_eq!(config.anomaly_action(), AnomalyAction::Refuse);
+        assert_eq!(config.max_table_change(), 0.5);
+        assert_eq!(config.max_country_change(), DEFAULT_MAX_COUNTRY_CHANGE);
+
+        std::fs::write(&path, "db_dir = \"/x\"\nlicence_key = \"KEY\"\n").unwrap();
+        assert!(GeoliteConfig::from_toml_file(&path).is_err());
+        assert!(GeoliteConfig::from_toml_file(dir.path().join("missing.toml")).is_err());
+    }
+
+    // The only test touching these variables, as tests share the process
+    #[test]
+    fn environment_variables() {
+        let vars = [
+            ("GL2_DBDIR", "/srv/geolite"),
+            ("MAXMIND_KEY", "KEY"),
+            ("GL2_EDITIONS", "city, ASN"),
+            ("GL2_SCHEDULE", "release-days"),
+            ("GL2_BASE_URL", "http://mirror.local//"),
+            ("GL2_MAX_ARCHIVE_SIZE", "1000"),
+            ("GL2_ERROR_POLICY", "collect"),
+            ("GL2_MIN_ROWS", "asn4=10, cities6 = 20"),
+            ("GL2_GOLDEN_IPS", "8.8.8.8=US,2001:4860::1=us"),
+            ("GL2_ANOMALY_ACTION", "off"),
+            ("GL2_MAX_COUNTRY_CHANGE", "0.3"),
+            ("GL2_MIN_COUNTRY_NETWORKS", "7"),
+        ];
+        for (k, v) in vars {
+            std::env::set_var(k, v);
+        }
+        let config = builder().license_key("OTHER").env().unwrap().build();
+        let bad = [
+            ("GL2_EDITIONS", "city,planet"),
+            ("GL2_SCHEDULE", "weekly"),
+            ("GL2_MIN_ROWS", "asn4"),
+            ("GL2_GOLDEN_IPS", "8.8.8.8"),
+            ("GL2_MAX_TABLE_CHANGE", "a lot"),
+            ("GL2_MAX_ARCHIVE_ENTRIES", "-1"),
+        ]
+        .map(|(k, v)| {
+            std::env::set_var(k, v);
+            let r = builder().env();
+            std::env::remove_var(k);
+            (k, r.is_err())
+        });
+        for (k, _) in vars {
+            std::env::remove_var(k);
+        }
+
+        let config = config.unwrap();
+        assert_eq!(config.db_dir(), Path::new("/srv/geolite"));
+        assert_eq!(config.license_key(), Some("KEY"));
+        assert_eq!(config.editions(), &[Edition::City, Edition::Asn]);
+        assert_eq!(config.schedule(), &Schedule::ReleaseDays);
+        assert_eq!(config.base_url(), "http://mirror.local");
+        assert_eq!(config.max_archive_size(), 1000);
+        assert_eq!(config.error_policy(), ErrorPolicy::Collect);
+        assert_eq!(
+            (config.min_rows("asn4"), config.min_rows("cities6")),
+            (10, 20)
+        );
+        assert_eq!(config.golden_ips()[1].country, "US");
+        assert_eq!(config.anomaly_action(), AnomalyAction::Off);
+        assert_eq!(config.max_country_change(), 0.3);
+        assert_eq!(config.min_country_networks(), 7);
+        for (k, err) in bad {
+            assert!(err, "bad {} was accepted", k);
+        }
+    }
+
+    #[test]
+    fn build_checks_and_defaults() {
+        let config = builder().build().unwrap();
+        assert_eq!(config.editions(), &Edition::ALL);
+        assert_eq!(config.base_url(), DEFAULT_BASE_URL);
+        assert_eq!(config.schedule(), &Schedule::default());
+        assert_eq!(config.error_policy(), ErrorPolicy::Abort);
+        assert_eq!(config.anomaly_action(), AnomalyAction::Warn);
+        assert_eq!(config.max_archive_size(), DEFAULT_MAX_ARCHIVE_SIZE);
+        assert_eq!(config.min_rows("countries"), 1);
+        assert!(
+            format!("{:?}", builder().license_key("SECRET").build().unwrap())
+                .contains("<redacted>")
+        );
+
+        let config = builder()
+            .editions(&[Edition::Asn, Edition::Country, Edition::Asn])
+            .base_url("http://mirror.local/")
+            .build()
+            .unwrap();
+        assert_eq!(config.editions(), &[Edition::Asn, Edition::Country]);
+        assert_eq!(config.base_url(), "http://mirror.local");
+
+        assert!(invalid(GeoliteConfig::builder().build()).contains("db_dir"));
+        assert!(invalid(builder().editions(&[]).build()).contains("editions"));
+        assert!(invalid(builder().min_rows("countries7", 1).build()).contains("countries7"));
+        for (table, country) in [(-0.1, 0.1), (0.1, -0.1), (f64::NAN, 0.1), (0.1, f64::NAN)] {
+            let r = builder()
+                .max_table_change(table)
+                .max_country_change(country)
+                .build();
+            assert!(invalid(r).contains("thresholds"));
+        }
+    }
+}
@@ -0,0 +1,720 @@
+use std::path::Path;
+
+use rusqlite::{CachedStatement, Connection, ToSql};
+use serde::{de::DeserializeOwned, Deserialize};
+use tokio::sync::mpsc;
+
+use crate::archive::{CsvSource, Record};
+use crate::config::{Edition, ErrorPolicy};
+use crate::db;
+use crate::errors::{GLErr, RowError};
+use crate::network;
+
+/// One GeoLite2 CSV file and how its rows are stored. `import` does the rest.
+pub(crate) trait Dataset: DeserializeOwned + Send + 'static {
+    /// File name inside the edition's archive.
+    const FILE: &'static str;
+    const TABLE: &'static str;
+    /// Columns of `TABLE` in the order `bind` pushes their values.
+    const COLUMNS: &'static [&'static str];
+
+    /// Fills in fields derived from the deserialized ones.
+    fn prepare(&mut self) -> Result<(), GLErr> {
+        Ok(())
+    }
+
+    fn bind<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>);
+}
+
+/// Trades durability for speed while a database is being built. A build
+/// that dies halfway is thrown away, so there is nothing to recover.
+pub(crate) fn tune_for_build(conn: &Connection) -> Result<(), GLErr> {
+    conn.execute_batch("PRAGMA journal_mode = OFF; PRAGMA synchronous = OFF;")?;
+    Ok(())
+}
+
+/// Creates the tables of `edition` in the database at `db` and imports
+/// every dataset of it from `source` over one connection. Returns the bad
+/// rows `policy` collected.
+pub(crate) async fn import_edition(
+    edition: Edition,
+    source: &CsvSource,
+    db: &Path,
+    policy: ErrorPolicy,
+) -> Result<Vec<RowError>, GLErr> {
+    let conn = Connection::open(db)?;
+    tune_for_build(&conn)?;
+    db::build_tables(&conn, &[edition])?;
+    let mut bad = Vec::new();
+    match edition {
+        Edition::Country => {
+            let conn = import::<Country>(source, conn, policy, &mut bad).await?;
+            let conn = import::<CountryIPv4>(source, conn, policy, &mut bad).await?;
+            import::<CountryIPv6>(source, conn, policy, &mut bad).await?;
+        }
+        Edition::City => {
+            let conn = import::<City>(source, conn, policy, &mut bad).await?;
+            let conn = import::<CityIPv4>(source, conn, policy, &mut bad).await?;
+            import::<CityIPv6>(source, conn, policy, &mut bad).await?;
+        }
+        Edition::Asn => {
+            let conn = import::<Asn4>(source, conn, policy, &mut bad).await?;
+            import::<Asn6>(source, conn, policy, &mut bad).await?;
+        }
+    }
+    Ok(bad)
+}
+
+/// Reads `D::FILE` from `source` and inserts its rows into `D::TABLE` in one
+/// transaction, handling bad rows as `policy` says. Inserting blocks, so
+/// `conn` is handed to a blocking thread and back.
+pub(crate) async fn import<D: Dataset>(
+    source: &CsvSource,
+    mut conn: Connection,
+    policy: ErrorPolicy,
+    bad: &mut Vec<RowError>,
+) -> Result<Connection, GLErr> {
+    let rows = source.rows::<D>(D::FILE);
+    println!("Populating {} from {}", D::TABLE, D::FILE);
+    let started = std::time::Instant::now();
+    let source = source.clone();
+    let (conn, result) = tokio::task::spawn_blocking(move || {
+        let result = insert::<D>(&mut conn, &source, rows, policy);
+        (conn, result)
+    })
+    .await?;
+    bad.extend(result?);
+    println!("Done!! Took: {:?}", started.elapsed());
+    Ok(conn)
+}
+
+fn insert<D: Dataset>(
+    conn: &mut Connection,
+    source: &CsvSource,
+    mut rows: mpsc::Receiver<Result<Record<D>, GLErr>>,
+    policy: ErrorPolicy,
+) -> Result<Vec<RowError>, GLErr> {
+    let q = format!(
+        "INSERT INTO {}({}) VALUES ({})",
+        D::TABLE,
+        D::COLUMNS.join(", "),
+        vec!["?"; D::COLUMNS.len()].join(",")
+    );
+    let mut collected = Vec::new();
+    let mut skipped = 0;
+    let mut path = None;
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare_cached(&q)?;
+        while let Some(record) = rows.blocking_recv() {
+            let record = record?;
+            let reason = match insert_row(&mut stmt, record.value)? {
+                Some(reason) => reason,
+                None => continue,
+            };
+            let e = RowError {
+                path: path.get_or_insert_with(|| source.location(D::FILE)).clone(),
+                line: record.line,
+                table: D::TABLE,
+                reason,
+            };
+            match policy {
+                ErrorPolicy::Abort => return Err(GLErr::BadRow(e)),
+                ErrorPolicy::Skip(max) if skipped == max => {
+                    return Err(GLErr::TooManyBadRows {
+                        table: D::TABLE,
+                        max,
+                        last: e,
+                    })
+                }
+                ErrorPolicy::Skip(_) => {
+                    eprintln!("Skipping {}", e);
+                    skipped += 1;
+                }
+                ErrorPolicy::Collect => collected.push(e),
+            }
+        }
+    }
+    tx.commit()?;
+    Ok(collected)
+}
+
+// `Some(reason)` when the row itself is bad, `Err` when the database is.
+fn insert_row<D: Dataset>(
+    stmt: &mut CachedStatement,
+    value: Result<D, csv::Error>,
+) -> Result<Option<String>, GLErr> {
+    let mut row = match value {
+        Ok(row) => row,
+        Err(e) => return Ok(Some(e.to_string())),
+    };
+    if let Err(e) = row.prepare() {
+        return Ok(Some(e.to_string()));
+    }
+    let mut params = Vec::with_capacity(D::COLUMNS.len());
+    row.bind(&mut params);
+    match stmt.execute(params.as_slice()) {
+        Ok(_) => Ok(None),
+        Err(rusqlite::Error::SqliteFailure(e, msg))
+            if e.code == rusqlite::ErrorCode::ConstraintViolation =>
+        {
+            Ok(Some(msg.unwrap_or_else(|| e.to_string())))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Country {
+    geoname_id: i64,
+    continent_code: String,
+    continent_name: String,
+    country_iso_code: String,
+    country_name: String,
+    is_in_european_union: i64,
+}
+
+impl Dataset for Country {
+    const FILE: &'static str = "GeoLite2-Country-Locations-en.csv";
+    const TABLE: &'static str = "countries";
+    const COLUMNS: &'static [&'static str] = &[
+        "geoname_id",
+        "continent_code",
+        "continent_name",
+        "country_iso_code",
+        "country_name",
+        "is_in_eu",
+    ];
+
+    fn bind<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) {
+        params.push(&self.geoname_id);
+        params.push(&self.continent_code);
+        params.push(&self.continent_name);
+        params.push(&self.country_iso_code);
+        params.push(&self.country_name);
+        params.push(&self.is_in_european_union);
+    }
+}
+
+const COUNTRY_BLOCK_COLUMNS: &[&str] = &[
+    "network",
+    "geoname_id",
+    "registered_country_geoname_id",
+    "is_anonymous_proxy",
+    "is_satellite_provider",
+    "network_start",
+    "network_end",
+];
+
+#[derive(Debug, Deserialize)]
+struct CountryIPv4 {
+    network: String,
+    geoname_id: Option<i64>,
+    registered_country_geoname_id: Option<i64>,
+    is_anonymous_proxy: i64,
+    is_satellite_provider: i64,
+    #[serde(skip)]
+    network_start: i64,
+    #[serde(skip)]
+    network_end: i64,
+}
+
+impl Dataset for CountryIPv4 {
+    const FILE: &'static str = "GeoLite2-Country-Blocks-IPv4.csv";
+    const TABLE: &'static str = "countries4";
+    const COLUMNS: &'static [&'static str] = COUNTRY_BLOCK_COLUMNS;
+
+    fn prepare(&mut self) -> Result<(), GLErr> {
+        let (start, end) = network::v4_range(&self.network)?;
+        self.network_start = start as i64;
+        self.network_end = end as i64;
+        Ok(())
+    }
+
+    fn bind<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) {
+        params.push(&self.network);
+        params.push(&self.geoname_id);
+        params.push(&self.registered_country_geoname_id);
+        params.push(&self.is_anonymous_proxy);
+        params.push(&self.is_satellite_provider);
+        params.push(&self.network_start);
+        params.push(&self.network_end);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CountryIPv6 {
+    network: String,
+    geoname_id: Option<i64>,
+    registered_country_geoname_id: Option<i64>,
+    is_anonymous_proxy: i64,
+    is_satellite_provider: i64,
+    #[serde(skip)]
+    network_start: Vec<u8>,
+    #[serde(skip)]
+    network_end: Vec<u8>,
+}
+
+impl Dataset for CountryIPv6 {
+    const FILE: &'static str = "GeoLite2-Country-Blocks-IPv6.csv";
+    const TABLE: &'static str = "countries6";
+    const COLUMNS: &'static [&'static str] = COUNTRY_BLOCK_COLUMNS;
+
+    fn prepare(&mut self) -> Result<(), GLErr> {
+        let (start, end) = network::v6_range(&self.network)?;
+        self.network_start = start.to_be_bytes().to_vec();
+        self.network_end = end.to_be_bytes().to_vec();
+        Ok(())
+    }
+
+    fn bind<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) {
+        params.push(&self.network);
+        params.push(&self.geoname_id);
+        params.push(&self.registered_country_geoname_id);
+        params.push(&self.is_anonymous_proxy);
+        params.push(&self.is_satellite_provider);
+        params.push(&self.network_start);
+        params.push(&self.network_end);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct City {
+    geoname_id: i64,
+    continent_code: String,
+    continent_name: String,
+    country_iso_code: String,
+    country_name: String,
+    subdivision_1_iso_code: String,
+    subdivision_1_name: String,
+    subdivision_2_iso_code: String,
+    subdivision_2_name: String,
+    city_name: String,
+    metro_code: String,
+    time_zone: String,
+    is_in_european_union: String,
+}
+
+impl Dataset for City {
+    const FILE: &'static str = "GeoLite2-City-Locations-en.csv";
+    const TABLE: &'static str = "cities";
+    const COLUMNS: &'static [&'static str] = &[
+        "geoname_id",
+        "continent_code",
+        "continent_name",
+        "country_iso_code",
+        "country_name",
+        "subdivision_1_iso_code",
+        "subdivision_1_name",
+        "subdivision_2_iso_code",
+        "subdivision_2_name",
+        "city_name",
+        "metro_code",
+        "time_zone",
+        "is_in_eu",
+    ];
+
+    fn bind<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) {
+        params.push(&self.geoname_id);
+        params.push(&self.continent_code);
+        params.push(&self.continent_name);
+        params.push(&self.country_iso_code);
+        params.push(&self.country_name);
+        params.push(&self.subdivision_1_iso_code);
+        params.push(&self.subdivision_1_name);
+        params.push(&self.subdivision_2_iso_code);
+        params.push(&self.subdivision_2_name);
+        params.push(&self.city_name);
+        params.push(&self.metro_code);
+        params.push(&self.time_zone);
+        params.push(&self.is_in_european_union);
+    }
+}
+
+const CITY_BLOCK_COLUMNS: &[&str] = &[
+    "network",
+    "geoname_id",
+    "registered_country_geoname_id",
+    "is_anonymous_proxy",
+    "is_satellite_provider",
+    "postal_code",
+    "latitude",
+    "longitude",
+    "accuracy_radius_km",
+    "network_start",
+    "network_end",
+];
+
+#[derive(Debug, Deserialize)]
+struct CityIPv4 {
+    network: String,
+    geoname_id: Option<i64>,
+    registered_country_geoname_id: Option<i64>,
+    is_anonymous_proxy: i64,
+    is_satellite_provider: i64,
+    postal_code: String,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    accuracy_radius: Option<i64>,
+    #[serde(skip)]
+    network_start: i64,
+    #[serde(skip)]
+    network_end: i64,
+}
+
+impl Dataset for CityIPv4 {
+    const FILE: &'static str = "GeoLite2-City-Blocks-IPv4.csv";
+    const TABLE: &'static str = "cities4";
+    const COLUMNS: &'static [&'static str] = CITY_BLOCK_COLUMNS;
+
+    fn prepare(&mut self) -> Result<(), GLErr> {
+        let (start, end) = network::v4_range(&self.network)?;
+        self.network_start = start as i64;
+        self.network_end = end as i64;
+        Ok(())
+    }
+
+    fn bind<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) {
+        params.push(&self.network);
+        params.push(&self.geoname_id);
+        params.push(&self.registered_country_geoname_id);
+        params.push(&self.is_anonymous_proxy);
+        params.push(&self.is_satellite_provider);
+        params.push(&self.postal_code);
+        params.push(&self.latitude);
+        params.push(&self.longitude);
+        params.push(&self.accuracy_radius);
+        params.push(&self.network_start);
+        params.push(&self.network_end);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct CityIPv6 {
+    network: String,
+    geoname_id: Option<i64>,
+    registered_country_geoname_id: Option<i64>,
+    is_anonymous_proxy: i64,
+    is_satellite_provider: i64,
+    postal_code: String,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    accuracy_radius: Option<i64>,
+    #[serde(skip)]
+    network_start: Vec<u8>,
+    #[serde(skip)]
+    network_end: Vec<u8>,
+}
+
+impl Dataset for CityIPv6 {
+    const FILE: &'static str = "GeoLite2-City-Blocks-IPv6.csv";
+    const TABLE: &'static str = "cities6";
+    const COLUMNS: &'static [&'static str] = CITY_BLOCK_COLUMNS;
+
+    fn prepare(&mut self) -> Result<(), GLErr> {
+        let (start, end) = network::v6_range(&self.network)?;
+        self.network_start = start.to_be_bytes().to_vec();
+        self.network_end = end.to_be_bytes().to_vec();
+        Ok(())
+    }
+
+    fn bind<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) {
+        params.push(&self.network);
+        params.push(&self.geoname_id);
+        params.push(&self.registered_country_geoname_id);
+        params.push(&self.is_anonymous_proxy);
+        params.push(&self.is_satellite_provider);
+        params.push(&self.postal_code);
+        params.push(&self.latitude);
+        params.push(&self.longitude);
+        params.push(&self.accuracy_radius);
+        params.push(&self.network_start);
+        params.push(&self.network_end);
+    }
+}
+
+const ASN_COLUMNS: &[&str] = &[
+    "network",
+    "autonomous_system_number",
+    "autonomous_system_org",
+    "network_start",
+    "network_end",
+];
+
+#[derive(Debug, Deserialize)]
+struct Asn4 {
+    network: String,
+    autonomous_system_number: i64,
+    autonomous_system_organization: String,
+    #[serde(skip)]
+    network_start: i64,
+    #[serde(skip)]
+    network_end: i64,
+}
+
+impl Dataset for Asn4 {
+    const FILE: &'static str = "GeoLite2-ASN-Blocks-IPv4.csv";
+    const TABLE: &'static str = "asn4";
+    const COLUMNS: &'static [&'static str] = ASN_COLUMNS;
+
+    fn prepare(&mut self) -> Result<(), GLErr> {
+        let (start, end) = network::v4_range(&self.network)?;
+        self.network_start = start as i64;
+        self.network_end = end as i64;
+        Ok(())
+    }
+
+    fn bind<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) {
+        params.push(&self.network);
+        params.push(&self.autonomous_system_number);
+        params.push(&self.autonomous_system_organization);
+        params.push(&self.network_start);
+        params.push(&self.network_end);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Asn6 {
+    network: String,
+    autonomous_system_number: i64,
+    autonomous_system_organization: String,
+    #[serde(skip)]
+    network_start: Vec<u8>,
+    #[serde(skip)]
+    network_end: Vec<u8>,
+}
+
+impl Dataset for Asn6 {
+    const FILE: &'static str = "GeoLite2-ASN-Blocks-IPv6.csv";
+    const TABLE: &'static str = "asn6";
+    const COLUMNS: &'static [&'static str] = ASN_COLUMNS;
+
+    fn prepare(&mut self) -> Result<(), GLErr> {
+        let (start, end) = network::v6_range(&self.network)?;
+        self.network_start = start.to_be_bytes().to_vec();
+        self.network_end = end.to_be_bytes().to_vec();
+        Ok(())
+    }
+
+    fn bind<'a>(&'a self, params: &mut Vec<&'a dyn ToSql>) {
+        params.push(&self.network);
+        params.push(&self.autonomous_system_number);
+        params.push(&self.autonomous_system_organization);
+        params.push(&self.network_start);
+        params.push(&self.network_end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNTRY_LOCATIONS: &str = "geoname_id,locale_code,continent_code,continent_name,country_iso_code,country_name,is_in_european_union
+2921044,en,EU,Europe,DE,Germany,1
+";
+    const COUNTRY_BLOCKS4: &str = "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider
+1.0.0.0/24,2921044,2921044,,0,0
+";
+    const COUNTRY_BLOCKS6: &str = "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider
+2001:200::/32,2921044,2921044,,0,0
+";
+    const CITY_LOCATIONS: &str = "geoname_id,locale_code,continent_code,continent_name,country_iso_code,country_name,subdivision_1_iso_code,subdivision_1_name,subdivision_2_iso_code,subdivision_2_name,city_name,metro_code,time_zone,is_in_european_union
+2950159,en,EU,Europe,DE,Germany,BE,\"Land Berlin\",,,Berlin,,Europe/Berlin,1
+";
+    const CITY_BLOCKS4: &str = "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider,postal_code,latitude,longitude,accuracy_radius
+1.0.1.0/24,2950159,2921044,,0,0,10115,52.5244,13.4105,20
+";
+    const CITY_BLOCKS6: &str = "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider,postal_code,latitude,longitude,accuracy_radius
+2001:201::/32,2950159,2921044,,0,0,10115,52.5244,13.4105,100
+";
+    const ASN_BLOCKS4: &str = "network,autonomous_system_number,autonomous_system_organization
+1.0.2.0/24,13335,CLOUDFLARENET
+";
+    // A malformed network on line 3 and a missing number on line 5.
+    const BAD_ASN_BLOCKS4: &str = "network,autonomous_system_number,autonomous_system_organization
+1.0.2.0/24,13335,CLOUDFLARENET
+1.0.3.0/33,13335,CLOUDFLARENET
+1.0.4.0/24,13335,CLOUDFLARENET
+1.0.5.0/24,,CLOUDFLARENET
+";
+    const ASN_BLOCKS6: &str = "network,autonomous_system_number,autonomous_system_organization
+2001:202::/32,2500,\"WIDE Project\"
+";
+
+    // Writes `files` into a temp dir, returning it and the path of a
+    // database holding every table. The dir is removed when dropped.
+    fn fixture(files: &[(&str, &str)]) -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        for (file, contents) in files {
+            std::fs::write(dir.path().join(file), contents).unwrap();
+        }
+        let path = dir.path().join("test.db");
+        db::build_tables(&rusqlite::Connection::open(&path).unwrap(), &Edition::ALL).unwrap();
+        (dir, path)
+    }
+
+    async fn import_fixture(
+        edition: Edition,
+        files: &[(&str, &str)],
+    ) -> (tempfile::TempDir, rusqlite::Connection) {
+        let (dir, path) = fixture(files);
+        let source = CsvSource::Dir(dir.path().to_path_buf());
+        import_edition(edition, &source, &path, ErrorPolicy::Abort)
+            .await
+            .unwrap();
+        (dir, rusqlite::Connection::open(path).unwrap())
+    }
+
+    // `network` (or `geoname_id` for location tables) of every row of every
+    // table, keyed by table.
+    fn contents(conn: &rusqlite::Connection) -> Vec<(&'static str, Vec<String>)> {
+        db::TABLES
+            .iter()
+            .map(|t| {
+                let key = match *t {
+                    "countries" | "cities" => "CAST(geoname_id AS TEXT)",
+                    _ => "network",
+                };
+                let mut stmt = conn.prepare(&format!("SELECT {} FROM {}", key, t)).unwrap();
+                let rows = stmt
+                    .query_map([], |r| r.get(0))
+                    .unwrap()
+                    .collect::<Result<Vec<String>, _>>()
+                    .unwrap();
+                (*t, rows)
+            })
+            .collect()
+    }
+
+    fn expect(only: &[(&'static str, &str)]) -> Vec<(&'static str, Vec<String>)> {
+        db::TABLES
+            .iter()
+            .map(|t| {
+                let rows = only
+                    .iter()
+                    .filter(|(table, _)| table == t)
+                    .map(|(_, key)| key.to_string())
+                    .collect();
+                (*t, rows)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn country_csvs_land_in_country_tables() {
+        let (_dir, conn) = import_fixture(
+            Edition::Country,
+            &[
+                (Country::FILE, COUNTRY_LOCATIONS),
+                (CountryIPv4::FILE, COUNTRY_BLOCKS4),
+                (CountryIPv6::FILE, COUNTRY_BLOCKS6),
+            ],
+        )
+        .await;
+        assert_eq!(
+            contents(&conn),
+            expect(&[
+                ("countries", "2921044"),
+                ("countries4", "1.0.0.0/24"),
+                ("countries6", "2001:200::/32"),
+            ])
+        );
+        let name: String = conn
+            .query_row("SELECT country_name FROM countries", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(name, "Germany");
+    }
+
+    #[tokio::test]
+    async fn city_csvs_land_in_city_tables() {
+        let (_dir, conn) = import_fixture(
+            Edition::City,
+            &[
+                (City::FILE, CITY_LOCATIONS),
+                (CityIPv4::FILE, CITY_BLOCKS4),
+                (CityIPv6::FILE, CITY_BLOCKS6),
+            ],
+        )
+        .await;
+        assert_eq!(
+            contents(&conn),
+            expect(&[
+                ("cities", "2950159"),
+                ("cities4", "1.0.1.0/24"),
+                ("cities6", "2001:201::/32"),
+            ])
+        );
+        let (city, postal): (String, String) = conn
+            .query_row(
+                "SELECT c.city_name, b.postal_code FROM cities4 b
+                JOIN cities c ON c.geoname_id = b.geoname_id",
+                [],
+                |r| Ok((r.get(0)?, r.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((city.as_str(), postal.as_str()), ("Berlin", "10115"));
+    }
+
+    #[tokio::test]
+    async fn asn_csvs_land_in_asn_tables() {
+        let (_dir, conn) = import_fixture(
+            Edition::Asn,
+            &[(Asn4::FILE, ASN_BLOCKS4), (Asn6::FILE, ASN_BLOCKS6)],
+        )
+        .await;
+        assert_eq!(
+            contents(&conn),
+            expect(&[("asn4", "1.0.2.0/24"), ("asn6", "2001:202::/32")])
+        );
+        let range: (i64, i64) = conn
+            .query_row("SELECT network_start, network_end FROM asn4", [], |r| {
+                Ok((r.get(0)?, r.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(range, (0x01000200, 0x010002ff));
+    }
+
+    #[tokio::test]
+    async fn bad_rows_follow_the_error_policy() {
+        let files = [(Asn4::FILE, BAD_ASN_BLOCKS4), (Asn6::FILE, ASN_BLOCKS6)];
+        let import = |policy| async move {
+            let (dir, path) = fixture(&files);
+            let source = CsvSource::Dir(dir.path().to_path_buf());
+            let result = import_edition(Edition::Asn, &source, &path, policy).await;
+            (result, rusqlite::Connection::open(path).unwrap(), dir)
+        };
+
+        let (result, conn, _dir) = import(ErrorPolicy::Abort).await;
+        match result {
+            Err(GLErr::BadRow(e)) => {
+                assert!(e.path.ends_with(Asn4::FILE), "{}", e.path);
+                assert_eq!((e.line, e.table), (3, "asn4"));
+            }
+            r => panic!("expected a bad row, got {:?}", r),
+        }
+        assert_eq!(contents(&conn), expect(&[]));
+
+        let (result, _, _dir) = import(ErrorPolicy::Skip(1)).await;
+        match result {
+            Err(GLErr::TooManyBadRows { table, max, last }) => {
+                assert_eq!((table, max, last.line), ("asn4", 1, 5));
+            }
+            r => panic!("expected too many bad rows, got {:?}", r),
+        }
+
+        let (result, conn, _dir) = import(ErrorPolicy::Skip(2)).await;
+        assert!(result.unwrap().is_empty());
+        assert_eq!(
+            contents(&conn),
+            expect(&[
+                ("asn4", "1.0.2.0/24"),
+                ("asn4", "1.0.4.0/24"),
+                ("asn6", "2001:202::/32"),
+            ])
+        );
+
+        let (result, _, _dir) = import(ErrorPolicy::Collect).await;
+        let lines: Vec<u64> = result.unwrap().iter().map(|e| e.line).collect();
+        assert_eq!(lines, vec![3, 5]);
+    }
+}
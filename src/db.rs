@@ -4,9 +4,16 @@ use std::{
     io::{Read, Write},
 };
 
-use crate::errors::GLErr;
-use rusqlite::ToSql;
-use serde::Deserialize;
+use crate::anomaly;
+use crate::archive::CsvSource;
+use crate::config::{AnomalyAction, Edition, ErrorPolicy, GeoliteConfig};
+use crate::dataset;
+use crate::download::{self, download};
+use crate::errors::{GLErr, RowError};
+use crate::inspect;
+use crate::schedule::format_utc;
+use crate::sources::{RemoteMeta, Sources};
+use sha2::{Digest, Sha256};
 
 fn build_table_counties(conn: &rusqlite::Connection) -> Result<(), GLErr> {
     conn.execute(
@@ -50,7 +57,9 @@ fn build_table_countries4(conn: &rusqlite::Connection) -> Result<(), GLErr> {
         geoname_id INT,
         registered_country_geoname_id INT,
         is_anonymous_proxy INT,
-        is_satellite_provider INT)",
+        is_satellite_provider INT,
+        network_start INT,
+        network_end INT)",
         (),
     )?;
     Ok(())
@@ -63,7 +72,9 @@ fn build_table_countries6(conn: &rusqlite::Connection) -> Result<(), GLErr> {
         geoname_id INT,
         registered_country_geoname_id INT,
         is_anonymous_proxy INT,
-        is_satellite_provider INT)",
+        is_satellite_provider INT,
+        network_start BLOB,
+        network_end BLOB)",
         (),
     )?;
     Ok(())
@@ -80,7 +91,9 @@ fn build_table_cities4(conn: &rusqlite::Connection) -> Result<(), GLErr> {
         postal_code TEXT,
         latitude REAL,
         longitude REAL,
-        accuracy_radius_km INT)",
+        accuracy_radius_km INT,
+        network_start INT,
+        network_end INT)",
         (),
     )?;
     Ok(())
@@ -97,7 +110,9 @@ fn build_table_cities6(conn: &rusqlite::Connection) -> Result<(), GLErr> {
         postal_code TEXT,
         latitude REAL,
         longitude REAL,
-        accuracy_radius_km INT)",
+        accuracy_radius_km INT,
+        network_start BLOB,
+        network_end BLOB)",
         (),
     )?;
     Ok(())
@@ -108,7 +123,9 @@ fn build_table_asn4(conn: &rusqlite::Connection) -> Result<(), GLErr> {
         "CREATE TABLE IF NOT EXISTS asn4 (
         network TEXT PRIMARY KEY,
         autonomous_system_number INT,
-        autonomous_system_org TEXT);",
+        autonomous_system_org TEXT,
+        network_start INT,
+        network_end INT);",
         (),
     )?;
     Ok(())
@@ -119,31 +136,90 @@ fn build_table_asn6(conn: &rusqlite::Connection) -> Result<(), GLErr> {
         "CREATE TABLE IF NOT EXISTS asn6 (
         network TEXT PRIMARY KEY,
         autonomous_system_number INT,
-        autonomous_system_org TEXT);",
+        autonomous_system_org TEXT,
+        network_start BLOB,
+        network_end BLOB);",
         (),
     )?;
     Ok(())
 }
 
-pub fn build_tables(conn: &rusqlite::Connection) -> Result<(), GLErr> {
-    build_table_counties(conn)?;
-    build_table_cities(conn)?;
-    build_table_countries4(conn)?;
-    build_table_countries6(conn)?;
-    build_table_cities4(conn)?;
-    build_table_cities6(conn)?;
-    build_table_asn4(conn)?;
-    build_table_asn6(conn)?;
+/// Every table `build_tables` can create.
+pub const TABLES: [&str; 8] = [
+    "countries",
+    "cities",
+    "countries4",
+    "countries6",
+    "cities4",
+    "cities6",
+    "asn4",
+    "asn6",
+];
+
+/// Creates the tables of `editions`.
+pub fn build_tables(conn: &rusqlite::Connection, editions: &[Edition]) -> Result<(), GLErr> {
+    for edition in editions {
+        match edition {
+            Edition::Country => {
+                build_table_counties(conn)?;
+                build_table_countries4(conn)?;
+                build_table_countries6(conn)?;
+            }
+            Edition::City => {
+                build_table_cities(conn)?;
+                build_table_cities4(conn)?;
+                build_table_cities6(conn)?;
+            }
+            Edition::Asn => {
+                build_table_asn4(conn)?;
+                build_table_asn6(conn)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Indexes the block tables of `editions` for lookups. Done once they are
+/// loaded, which is much faster than keeping the indexes up to date row by
+/// row.
+pub fn build_indexes(conn: &rusqlite::Connection, editions: &[Edition]) -> Result<(), GLErr> {
+    for edition in editions {
+        for table in edition.tables() {
+            if matches!(*table, "countries" | "cities") {
+                continue;
+            }
+            conn.execute(
+                &format!(
+                    "CREATE INDEX IF NOT EXISTS {0}_network_start ON {0}(network_start)",
+                    table
+                ),
+                (),
+            )?;
+        }
+    }
     Ok(())
 }
 
-fn update_needed<P: AsRef<std::path::Path>>(dbdir: P) -> bool {
-    let p = dbdir.as_ref().join("version");
+/// Editions whose tables are all present in `conn`.
+pub fn built_editions(conn: &rusqlite::Connection) -> Result<Vec<Edition>, GLErr> {
+    let mut stmt = conn.prepare("SELECT name FROM sqlite_master WHERE type = 'table'")?;
+    let names = stmt
+        .query_map([], |r| r.get::<_, String>(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+    Ok(Edition::ALL
+        .into_iter()
+        .filter(|e| e.tables().iter().all(|t| names.iter().any(|n| n == t)))
+        .collect())
+}
+
+/// When the configured database was last built, if it has been.
+pub fn last_update(config: &GeoliteConfig) -> Option<std::time::SystemTime> {
+    let p = config.db_dir().join("version");
     let mut f = match File::open(p) {
         Ok(p) => p,
         Err(e) => {
             eprintln!("{:?}", e);
-            return true;
+            return None;
         }
     };
     let mut s = String::new();
@@ -151,749 +227,270 @@ fn update_needed<P: AsRef<std::path::Path>>(dbdir: P) -> bool {
         Ok(_) => {}
         Err(e) => {
             eprintln!("{:?}", e);
-            return true;
+            return None;
         }
     }
-    let secs = match s.parse::<u64>() {
+    let secs = match s.trim().parse::<u64>() {
         Ok(d) => d,
         Err(e) => {
             eprintln!("{:?}", e);
-            return true;
-        }
-    };
-    let last = std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs);
-    let ds = match std::time::SystemTime::now().duration_since(last) {
-        Ok(ds) => ds,
-        Err(e) => {
-            eprintln!("{:?}", e);
-            return true;
+            return None;
         }
     };
-    if ds < std::time::Duration::from_secs(3600 * 24 * 7) {
-        eprintln!("It has been less than 7 days since last download");
-        return false;
-    }
-    true
+    Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs))
 }
 
-async fn download<P: AsRef<std::path::Path>>(url: &str, path: P) -> Result<(), GLErr> {
-    println!("Downloading to: {:?}", path.as_ref());
-    let res = reqwest::get(url).await?.bytes().await?;
-    let mut f = File::create(path.as_ref())?;
-    f.write_all(res.as_ref())?;
-    Ok(())
+/// When the configured database is next due for a rebuild under its schedule,
+/// counted from the later of the last build and the last check that found
+/// nothing new. A directory without a database is due immediately.
+pub fn next_update(config: &GeoliteConfig) -> std::time::SystemTime {
+    let last = match last_update(config) {
+        Some(last) => last,
+        None => return std::time::UNIX_EPOCH,
+    };
+    let checked = std::time::UNIX_EPOCH
+        + std::time::Duration::from_secs(Sources::read(config.db_dir()).checked);
+    config.schedule().next_after(last.max(checked))
 }
 
-async fn get_db_files<P: AsRef<std::path::Path>>(dbf_path: P) -> Result<(), GLErr> {
-    let dbf_path = dbf_path.as_ref();
-    std::fs::create_dir_all(dbf_path)?;
-    let key = std::env::var("MAXMIND_KEY")?;
-    // Countries
-    let perma = format!(
-        "https://download.maxmind.com/app/geoip_download?edition_id=GeoLite2-Country-CSV&license_key={}&suffix=zip",
-        key,
-    );
-    let countriesf = dbf_path.join("countries.zip");
-    download(perma.as_str(), &countriesf).await?;
-    // unzip
-    let f = File::open(&countriesf)?;
-    let mut z = zip::ZipArchive::new(f)?;
-    z.extract(&dbf_path.join("countries"))?;
-
-    // Cities
-    let perma = format!(
-        "https://download.maxmind.com/app/geoip_download?edition_id=GeoLite2-City-CSV&license_key={}&suffix=zip",
-        key,
-    );
-    let citiesf = dbf_path.join("cities.zip");
-    download(perma.as_str(), &citiesf).await?;
-    let f = File::open(&citiesf)?;
-    let mut z = zip::ZipArchive::new(f)?;
-    z.extract(&dbf_path.join("cities"))?;
-
-    // ASN
-    let perma = format!(
-        "https://download.maxmind.com/app/geoip_download?edition_id=GeoLite2-ASN-CSV&license_key={}&suffix=zip",
-        key,
-    );
-    let asnf = dbf_path.join("asn.zip");
-    download(perma.as_str(), &asnf).await?;
-    let f = File::open(&asnf)?;
-    let mut z = zip::ZipArchive::new(f)?;
-    z.extract(&dbf_path.join("asn"))?;
-
-    // Unzip
-    Ok(())
+// Whether the live database holds other editions than the configured ones.
+fn editions_changed(config: &GeoliteConfig) -> bool {
+    let conn = match rusqlite::Connection::open_with_flags(
+        config.db_path(),
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    ) {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
+    match built_editions(&conn) {
+        Ok(built) => {
+            built.len() != config.editions().len()
+                || config.editions().iter().any(|e| !built.contains(e))
+        }
+        Err(_) => false,
+    }
 }
 
-fn execute_query(db: String, q: &str, params: &[&dyn ToSql]) -> Result<(), GLErr> {
-    let conn = rusqlite::Connection::open(&db)?;
-    conn.execute(q, params)?;
-    Ok(())
+// Whether the database at `path` has block tables without the
+// `network_start`/`network_end` columns lookups need, as those built before
+// they were added do.
+fn ranges_missing(path: &std::path::Path) -> bool {
+    let conn = match inspect::open(path) {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
+    let tables = match built_editions(&conn) {
+        Ok(built) => built.into_iter().flat_map(|e| e.tables().iter()),
+        Err(_) => return false,
+    };
+    for table in tables.filter(|t| !matches!(**t, "countries" | "cities")) {
+        let columns: Vec<String> = match conn
+            .prepare("SELECT name FROM pragma_table_info(?1)")
+            .and_then(|mut stmt| stmt.query_map([table], |r| r.get(0))?.collect())
+        {
+            Ok(c) => c,
+            Err(_) => return false,
+        };
+        if !["network_start", "network_end"]
+            .iter()
+            .all(|n| columns.iter().any(|c| c == n))
+        {
+            return true;
+        }
+    }
+    false
 }
 
-#[derive(Debug, Deserialize)]
-struct Country {
-    geoname_id: i64,
-    continent_code: String,
-    continent_name: String,
-    country_iso_code: String,
-    country_name: String,
-    is_in_european_union: i64,
+fn update_needed(config: &GeoliteConfig) -> bool {
+    let next = next_update(config);
+    if std::time::SystemTime::now() < next {
+        let secs = next
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        eprintln!("Next update is due at {}", format_utc(secs));
+        return false;
+    }
+    true
 }
 
-#[derive(Deserialize, Debug)]
-struct CountryIPv4 {
-    network: String,
-    geoname_id: Option<i64>,
-    registered_country_geoname_id: Option<i64>,
-    is_anonymous_proxy: i64,
-    is_satellite_provider: i64,
+// With an account id the license key is sent as Basic-auth to MaxMind's
+// current permalinks; without one it goes in the legacy permalink's query.
+fn edition_request(
+    client: &reqwest::Client,
+    method: reqwest::Method,
+    config: &GeoliteConfig,
+    edition: Edition,
+    suffix: &str,
+) -> Result<reqwest::RequestBuilder, GLErr> {
+    let key = config
+        .license_key()
+        .ok_or_else(|| GLErr::InvalidConfig(String::from("license_key is not set")))?;
+    Ok(match config.account_id() {
+        Some(account_id) => client
+            .request(
+                method,
+                format!(
+                    "{}/geoip/databases/{}/download?suffix={}",
+                    config.base_url(),
+                    edition.id(),
+                    suffix,
+                ),
+            )
+            .basic_auth(account_id, Some(key)),
+        None => client.request(
+            method,
+            format!(
+                "{}/app/geoip_download?edition_id={}&license_key={}&suffix={}",
+                config.base_url(),
+                edition.id(),
+                key,
+                suffix,
+            ),
+        ),
+    })
 }
 
-#[derive(Deserialize, Debug)]
-struct CountryIPv6 {
-    network: String,
-    geoname_id: Option<i64>,
-    registered_country_geoname_id: Option<i64>,
-    is_anonymous_proxy: i64,
-    is_satellite_provider: i64,
+// Asks MaxMind, with HEAD requests, whether any configured edition changed
+// since the live database was built.
+async fn remote_changed(config: &GeoliteConfig) -> Result<bool, GLErr> {
+    if !config.db_path().exists() {
+        return Ok(true);
+    }
+    let sources = Sources::read(config.db_dir());
+    let client = reqwest::Client::new();
+    for edition in config.editions() {
+        let known = match sources.get(*edition) {
+            Some(m) => m,
+            None => return Ok(true),
+        };
+        let res = edition_request(&client, reqwest::Method::HEAD, config, *edition, "zip")?
+            .send()
+            .await?
+            .error_for_status()?;
+        let remote = RemoteMeta::from_headers(res.headers());
+        let rejected = sources
+            .rejected(*edition)
+            .is_some_and(|r| r.same_release(&remote));
+        if !known.same_release(&remote) && !rejected {
+            return Ok(true);
+        }
+    }
+    Ok(false)
 }
 
-async fn countries_from_csv<P: AsRef<std::path::Path>>(
-    dbfiles: P,
-    db: String,
-) -> Result<(), GLErr> {
-    let cdir = dbfiles.as_ref().join(std::path::Path::new("countries"));
-    let mut path = if let Some(v) = (std::fs::read_dir(&cdir)?).next() {
-        match v {
-            Ok(p) => p.path(),
-            Err(e) => return Err(GLErr::IOErr(e)),
-        }
-    } else {
-        return Err(GLErr::ZipExtractErr);
-    };
+async fn expected_sha256(edition: Edition, req: reqwest::RequestBuilder) -> Result<String, GLErr> {
+    let body = req.send().await?.error_for_status()?.text().await?;
+    parse_sha256(edition, &body)
+}
 
-    // Countries
-    path.push("GeoLite2-Country-Locations-en.csv");
-    let mut reader = csv::Reader::from_path(&path)?;
-    println!("Populating Country Locations");
-    let started = std::time::Instant::now();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<Country>>(10);
-    let db1 = db.clone();
-    // tokio::sync::Pin
-    let jh = tokio::spawn(async move {
-        let mut countries = Vec::<Country>::new();
-        let mut count: usize = 0;
-        let batch_size: usize = 5000;
-        let db = db1;
-        fn execute(cities: &[Country], db: String) {
-            let mut values = Vec::<String>::new();
-            let mut params = Vec::<&dyn rusqlite::ToSql>::new();
-            let mut q = String::from(
-                "INSERT INTO cities(
-                    geoname_id, continent_code, continent_name, country_iso_code,
-                    country_name, is_in_eu
-            ) VALUES",
-            );
-            for c in cities.iter() {
-                values.push(String::from("(?,?,?,?,?,?)"));
-                params.push(&c.geoname_id);
-                params.push(&c.continent_code);
-                params.push(&c.continent_name);
-                params.push(&c.country_iso_code);
-                params.push(&c.country_name);
-                params.push(&c.is_in_european_union);
-            }
-            q = format!("{} {}", q, values.join(","));
-            match execute_query(db, &q, params.as_slice()) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("{:?}", e);
-                }
-            }
+// MaxMind's `.sha256` companions read "<hex digest>  <archive name>".
+// Anything else, such as an error page, is refused rather than compared.
+fn parse_sha256(edition: Edition, body: &str) -> Result<String, GLErr> {
+    match body.split_whitespace().next() {
+        Some(d) if d.len() == 64 && d.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            Ok(d.to_ascii_lowercase())
         }
-        while let Some(country) = rx.recv().await {
-            if count == batch_size {
-                execute(&countries, db.clone());
-                countries = Vec::<Country>::new();
-                count = 0;
-            }
-            match country {
-                Some(v) => {
-                    countries.push(v);
-                    count += 1;
-                }
-                None => {
-                    execute(&countries, db.clone());
-                    break;
-                }
-            };
-        }
-    });
-    for result in reader.deserialize() {
-        let c: Country = result?;
-        let _ = tx.send(Some(c)).await;
-    }
-    let _ = tx.send(None).await;
-    jh.await?;
-    println!("Done!! Took: {:?}", started.elapsed());
-    path.pop();
-
-    // ipv4
-    path.push("GeoLite2-Country-Blocks-IPv4.csv");
-    let mut reader = csv::Reader::from_path(&path)?;
-    println!("Populating Country Blocks IPv4");
-    let started = std::time::Instant::now();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<CountryIPv4>>(10);
-    let db1 = db.clone();
-    // tokio::sync::Pin
-    let jh = tokio::spawn(async move {
-        let mut countries = Vec::<CountryIPv4>::new();
-        let mut count: usize = 0;
-        let batch_size: usize = 20000;
-        let db = db1;
-        fn execute(countries: &[CountryIPv4], db: String) {
-            let mut values = Vec::<String>::new();
-            let mut params = Vec::<&dyn rusqlite::ToSql>::new();
-            let mut q = String::from(
-                "INSERT INTO countries4(
-                    network, geoname_id, registered_country_geoname_id, is_anonymous_proxy, is_satellite_provider
-            ) VALUES",
-            );
-            for c in countries.iter() {
-                values.push(String::from("(?,?,?,?,?)"));
-                params.push(&c.network);
-                params.push(&c.geoname_id);
-                params.push(&c.registered_country_geoname_id);
-                params.push(&c.is_anonymous_proxy);
-                params.push(&c.is_satellite_provider);
-            }
-            q = format!("{} {}", q, values.join(","));
-            match execute_query(db, &q, params.as_slice()) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("{:?}", e);
-                }
-            }
-        }
-        while let Some(country4) = rx.recv().await {
-            if count == batch_size {
-                execute(&countries, db.clone());
-                countries = Vec::<CountryIPv4>::new();
-                count = 0;
-            }
-            match country4 {
-                Some(v) => {
-                    countries.push(v);
-                    count += 1;
-                }
-                None => {
-                    execute(&countries, db.clone());
-                    break;
-                }
-            };
-        }
-    });
-    for result in reader.deserialize() {
-        let c: CountryIPv4 = result?;
-        let _ = tx.send(Some(c)).await;
-    }
-    let _ = tx.send(None).await;
-    jh.await?;
-    println!("Done!! Took: {:?}", started.elapsed());
-    path.pop();
-
-    // ipv6
-    path.push("GeoLite2-Country-Blocks-IPv6.csv");
-    let mut reader = csv::Reader::from_path(&path)?;
-    println!("Populating Country Blocks IPv6");
-    let started = std::time::Instant::now();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<CountryIPv6>>(10);
-    let db1 = db.clone();
-    // tokio::sync::Pin
-    let jh = tokio::spawn(async move {
-        let mut countries = Vec::<CountryIPv6>::new();
-        let mut count: usize = 0;
-        let batch_size: usize = 20000;
-        let db = db1;
-        fn execute(countries: &[CountryIPv6], db: String) {
-            let mut values = Vec::<String>::new();
-            let mut params = Vec::<&dyn rusqlite::ToSql>::new();
-            let mut q = String::from(
-                "INSERT INTO countries6(
-                    network, geoname_id, registered_country_geoname_id, is_anonymous_proxy, is_satellite_provider
-            ) VALUES",
-            );
-            for c in countries.iter() {
-                values.push(String::from("(?,?,?,?,?)"));
-                params.push(&c.network);
-                params.push(&c.geoname_id);
-                params.push(&c.registered_country_geoname_id);
-                params.push(&c.is_anonymous_proxy);
-                params.push(&c.is_satellite_provider);
-            }
-            q = format!("{} {}", q, values.join(","));
-            match execute_query(db, &q, params.as_slice()) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("{:?}", e);
-                }
-            };
-        }
-        while let Some(country4) = rx.recv().await {
-            if count == batch_size {
-                execute(&countries, db.clone());
-                countries = Vec::<CountryIPv6>::new();
-                count = 0;
-            }
-            match country4 {
-                Some(v) => {
-                    countries.push(v);
-                    count += 1;
-                }
-                None => {
-                    execute(&countries, db.clone());
-                    break;
-                }
-            };
-        }
-    });
-    for result in reader.deserialize() {
-        let c: CountryIPv6 = result?;
-        let _ = tx.send(Some(c)).await;
-    }
-    let _ = tx.send(None).await;
-    jh.await?;
-    println!("Done!! Took: {:?}", started.elapsed());
-    path.pop();
-    Ok(())
+        _ => Err(GLErr::MalformedChecksum {
+            edition,
+            body: body.chars().take(100).collect(),
+        }),
+    }
 }
 
-#[derive(Debug, Deserialize)]
-struct City {
-    geoname_id: i64,
-    continent_code: String,
-    continent_name: String,
-    country_iso_code: String,
-    country_name: String,
-    subdivision_1_iso_code: String,
-    subdivision_1_name: String,
-    subdivision_2_iso_code: String,
-    subdivision_2_name: String,
-    city_name: String,
-    metro_code: String,
-    time_zone: String,
-    is_in_european_union: String,
-}
-
-#[derive(Debug, Deserialize)]
-struct CityIPv4 {
-    network: String,
-    geoname_id: Option<i64>,
-    registered_country_geoname_id: Option<i64>,
-    is_anonymous_proxy: i64,
-    is_satellite_provider: i64,
-    postal_code: String,
-    latitude: Option<f64>,
-    longitude: Option<f64>,
-    accuracy_radius: Option<i64>,
-}
-
-#[derive(Debug, Deserialize)]
-struct CityIPv6 {
-    network: String,
-    geoname_id: Option<i64>,
-    registered_country_geoname_id: Option<i64>,
-    is_anonymous_proxy: i64,
-    is_satellite_provider: i64,
-    postal_code: String,
-    latitude: Option<f64>,
-    longitude: Option<f64>,
-    accuracy_radius: Option<i64>,
-}
-
-async fn cities_from_csv<P: AsRef<std::path::Path>>(dbfiles: P, db: String) -> Result<(), GLErr> {
-    let cdir = dbfiles.as_ref().join(std::path::Path::new("cities"));
-    let mut path = if let Some(v) = (std::fs::read_dir(&cdir)?).next() {
-        match v {
-            Ok(p) => p.path(),
-            Err(e) => return Err(GLErr::IOErr(e)),
-        }
-    } else {
-        return Err(GLErr::ZipExtractErr);
-    };
+fn sha256_file<P: AsRef<std::path::Path>>(path: P) -> Result<String, GLErr> {
+    let mut f = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut f, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
 
-    // City
-    path.push("GeoLite2-City-Locations-en.csv");
-    let mut reader = csv::Reader::from_path(&path)?;
-    println!("Populating City Locations");
-    let started = std::time::Instant::now();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<City>>(10);
-    let db1 = db.clone();
-    // tokio::sync::Pin
-    let jh = tokio::spawn(async move {
-        let mut cities = Vec::<City>::new();
-        let mut count: usize = 0;
-        let batch_size: usize = 5000;
-        let db = db1;
-        fn execute(cities: &[City], db: String) {
-            let mut values = Vec::<String>::new();
-            let mut params = Vec::<&dyn rusqlite::ToSql>::new();
-            let mut q = String::from(
-                "INSERT INTO cities(
-                geoname_id, continent_code, continent_name, country_iso_code,
-                country_name, subdivision_1_iso_code, subdivision_1_name,
-                subdivision_2_iso_code, subdivision_2_name, city_name,
-                metro_code, time_zone, is_in_eu
-            ) VALUES",
-            );
-            for c in cities.iter() {
-                values.push(String::from("(?,?,?,?,?,?,?,?,?,?,?,?,?)"));
-                params.push(&c.geoname_id);
-                params.push(&c.continent_code);
-                params.push(&c.continent_name);
-                params.push(&c.country_iso_code);
-                params.push(&c.country_name);
-                params.push(&c.subdivision_1_iso_code);
-                params.push(&c.subdivision_1_name);
-                params.push(&c.subdivision_2_iso_code);
-                params.push(&c.subdivision_2_name);
-                params.push(&c.city_name);
-                params.push(&c.metro_code);
-                params.push(&c.time_zone);
-                params.push(&c.is_in_european_union);
-            }
-            q = format!("{} {}", q, values.join(","));
-            match execute_query(db, &q, params.as_slice()) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("{:?}", e);
-                }
-            }
-        }
-        while let Some(city) = rx.recv().await {
-            if count == batch_size {
-                execute(&cities, db.clone());
-                cities = Vec::<City>::new();
-                count = 0;
-            }
-            match city {
-                Some(v) => {
-                    cities.push(v);
-                    count += 1;
-                }
-                None => {
-                    execute(&cities, db.clone());
-                    break;
-                }
-            };
-        }
-    });
-    for result in reader.deserialize() {
-        let c: City = result?;
-        let _ = tx.send(Some(c)).await;
-    }
-    let _ = tx.send(None).await;
-    jh.await?;
-    println!("Done!! Took: {:?}", started.elapsed());
-    path.pop();
-
-    // ipv4
-    path.push("GeoLite2-City-Blocks-IPv4.csv");
-    let mut reader = csv::Reader::from_path(&path)?;
-    println!("Populating City Blocks IPv4");
-    let started = std::time::Instant::now();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<CityIPv4>>(10);
-    let db1 = db.clone();
-    // tokio::sync::Pin
-    let jh = tokio::spawn(async move {
-        let mut cities = Vec::<CityIPv4>::new();
-        let mut count: usize = 0;
-        let batch_size: usize = 100000;
-        let db = db1;
-        fn execute(cities: &[CityIPv4], db: String) {
-            let mut values = Vec::<String>::new();
-            let mut params = Vec::<&dyn rusqlite::ToSql>::new();
-            let mut q = String::from(
-                "INSERT INTO cities4(
-                    network, geoname_id, registered_country_geoname_id, is_anonymous_proxy, is_satellite_provider,
-                    postal_code, latitude, longitude, accuracy_radius_km
-            ) VALUES",
-            );
-            for c in cities.iter() {
-                values.push(String::from("(?,?,?,?,?,?,?,?,?)"));
-                params.push(&c.network);
-                params.push(&c.geoname_id);
-                params.push(&c.registered_country_geoname_id);
-                params.push(&c.is_anonymous_proxy);
-                params.push(&c.is_satellite_provider);
-                params.push(&c.postal_code);
-                params.push(&c.latitude);
-                params.push(&c.longitude);
-                params.push(&c.accuracy_radius);
-            }
-            q = format!("{} {}", q, values.join(","));
-            match execute_query(db, &q, params.as_slice()) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("{:?}", e);
-                }
-            }
-        }
-        while let Some(city4) = rx.recv().await {
-            if count == batch_size {
-                execute(&cities, db.clone());
-                cities = Vec::<CityIPv4>::new();
-                count = 0;
-            }
-            match city4 {
-                Some(v) => {
-                    cities.push(v);
-                    count += 1;
-                }
-                None => {
-                    execute(&cities, db.clone());
-                    break;
-                }
-            };
-        }
-    });
-    for result in reader.deserialize() {
-        let c: CityIPv4 = result?;
-        let _ = tx.send(Some(c)).await;
-    }
-    let _ = tx.send(None).await;
-    jh.await?;
-    println!("Done!! Took: {:?}", started.elapsed());
-    path.pop();
-
-    // ipv6
-    path.push("GeoLite2-City-Blocks-IPv6.csv");
-    let mut reader = csv::Reader::from_path(&path)?;
-    println!("Populating City Blocks IPv6");
-    let started = std::time::Instant::now();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<CityIPv6>>(10);
-    let db1 = db.clone();
-    // tokio::sync::Pin
-    let jh = tokio::spawn(async move {
-        let mut cities = Vec::<CityIPv6>::new();
-        let mut count: usize = 0;
-        let batch_size: usize = 100000;
-        let db = db1;
-        fn execute(cities: &[CityIPv6], db: String) {
-            let mut values = Vec::<String>::new();
-            let mut params = Vec::<&dyn rusqlite::ToSql>::new();
-            let mut q = String::from(
-                "INSERT INTO cities6(
-                    network, geoname_id, registered_country_geoname_id, is_anonymous_proxy, is_satellite_provider,
-                    postal_code, latitude, longitude, accuracy_radius_km
-            ) VALUES",
-            );
-            for c in cities.iter() {
-                values.push(String::from("(?,?,?,?,?,?,?,?,?)"));
-                params.push(&c.network);
-                params.push(&c.geoname_id);
-                params.push(&c.registered_country_geoname_id);
-                params.push(&c.is_anonymous_proxy);
-                params.push(&c.is_satellite_provider);
-                params.push(&c.postal_code);
-                params.push(&c.latitude);
-                params.push(&c.longitude);
-                params.push(&c.accuracy_radius);
-            }
-            q = format!("{} {}", q, values.join(","));
-            match execute_query(db, &q, params.as_slice()) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("{:?}", e);
-                }
-            }
-        }
-        while let Some(city) = rx.recv().await {
-            if count == batch_size {
-                execute(&cities, db.clone());
-                cities = Vec::<CityIPv6>::new();
-                count = 0;
-            }
-            match city {
-                Some(v) => {
-                    cities.push(v);
-                    count += 1;
-                }
-                None => {
-                    execute(&cities, db.clone());
-                    break;
-                }
-            };
-        }
-    });
-    for result in reader.deserialize() {
-        let c: CityIPv6 = result?;
-        let _ = tx.send(Some(c)).await;
+async fn verify_download<P: AsRef<std::path::Path>>(
+    edition: Edition,
+    sha_req: reqwest::RequestBuilder,
+    path: P,
+) -> Result<(), GLErr> {
+    let expected = expected_sha256(edition, sha_req).await?;
+    let actual = sha256_file(path)?;
+    if expected != actual {
+        return Err(GLErr::ChecksumMismatch {
+            edition,
+            expected,
+            actual,
+        });
     }
-    let _ = tx.send(None).await;
-    jh.await?;
-    println!("Done!! Took: {:?}", started.elapsed());
-    path.pop();
-
     Ok(())
 }
 
-#[derive(Debug, Deserialize)]
-struct ASN4 {
-    network: String,
-    autonomous_system_number: i64,
-    autonomous_system_organization: String,
+// Downloads and verifies one edition's archive into `dbf_path`.
+async fn get_edition_files(
+    client: &reqwest::Client,
+    config: &GeoliteConfig,
+    edition: Edition,
+    dbf_path: &std::path::Path,
+) -> Result<(RemoteMeta, CsvSource), GLErr> {
+    let zipf = dbf_path.join(format!("{}.zip", edition.dir_name()));
+    let req = edition_request(client, reqwest::Method::GET, config, edition, "zip")?;
+    let meta = download(req, &zipf, edition, config.progress()).await?;
+    let sha_req = edition_request(client, reqwest::Method::GET, config, edition, "zip.sha256")?;
+    verify_download(edition, sha_req, &zipf).await?;
+    Ok((meta, CsvSource::new(&zipf, config)))
 }
 
-#[derive(Debug, Deserialize)]
-struct ASN6 {
-    network: String,
-    autonomous_system_number: i64,
-    autonomous_system_organization: String,
+// Downloads every configured edition, returning what they were built from and
+// each one's archive.
+async fn get_db_files<P: AsRef<std::path::Path>>(
+    config: &GeoliteConfig,
+    dbf_path: P,
+) -> Result<(Sources, Vec<(Edition, CsvSource)>), GLErr> {
+    let dbf_path = dbf_path.as_ref();
+    std::fs::create_dir_all(dbf_path)?;
+    let client = reqwest::Client::new();
+    let mut handles = Vec::new();
+    for edition in config.editions() {
+        let (client, config, edition) = (client.clone(), config.clone(), *edition);
+        let dbf_path = dbf_path.to_path_buf();
+        handles.push(tokio::spawn(async move {
+            get_edition_files(&client, &config, edition, &dbf_path).await
+        }));
+    }
+    let mut sources = Sources::default();
+    let mut archives = Vec::new();
+    for (edition, (meta, source)) in config.editions().iter().zip(join_all(handles).await?) {
+        sources.set(*edition, meta);
+        archives.push((*edition, source));
+    }
+    Ok((sources, archives))
 }
 
-async fn asn_from_csv<P: AsRef<std::path::Path>>(dbfiles: P, db: String) -> Result<(), GLErr> {
-    let cdir = dbfiles.as_ref().join(std::path::Path::new("asn"));
-    let mut path = if let Some(v) = (std::fs::read_dir(&cdir)?).next() {
-        match v {
-            Ok(p) => p.path(),
-            Err(e) => return Err(GLErr::IOErr(e)),
-        }
-    } else {
-        return Err(GLErr::ZipExtractErr);
-    };
-
-    // ipv4
-    path.push("GeoLite2-ASN-Blocks-IPv4.csv");
-    let mut reader = csv::Reader::from_path(&path)?;
-    println!("Populating ASN Blocks IPv4");
-    let started = std::time::Instant::now();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<ASN4>>(10);
-    let db1 = db.clone();
-    // tokio::sync::Pin
-    let jh = tokio::spawn(async move {
-        let mut asns = Vec::<ASN4>::new();
-        let mut count: usize = 0;
-        let batch_size: usize = 100000;
-        let db = db1;
-        fn execute(asns: &[ASN4], db: String) {
-            let mut values = Vec::<String>::new();
-            let mut params = Vec::<&dyn rusqlite::ToSql>::new();
-            let mut q = String::from(
-                "INSERT INTO cities4(
-                    network,autonomous_system_number,autonomous_system_org
-            ) VALUES",
-            );
-            for a in asns.iter() {
-                values.push(String::from("(?,?,?)"));
-                params.push(&a.network);
-                params.push(&a.autonomous_system_number);
-                params.push(&a.autonomous_system_organization);
-            }
-            q = format!("{} {}", q, values.join(","));
-            match execute_query(db, &q, params.as_slice()) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("{:?}", e);
-                }
+// Waits for every task, aborting the rest as soon as one fails.
+async fn join_all<T>(
+    mut handles: Vec<tokio::task::JoinHandle<Result<T, GLErr>>>,
+) -> Result<Vec<T>, GLErr> {
+    let mut out = Vec::new();
+    for i in 0..handles.len() {
+        let res = match (&mut handles[i]).await {
+            Ok(res) => res,
+            Err(e) => Err(e.into()),
+        };
+        match res {
+            Ok(v) => out.push(v),
+            Err(e) => {
+                handles[i + 1..].iter().for_each(|h| h.abort());
+                return Err(e);
             }
         }
-        while let Some(asn) = rx.recv().await {
-            if count == batch_size {
-                execute(&asns, db.clone());
-                asns = Vec::<ASN4>::new();
-                count = 0;
-            }
-            match asn {
-                Some(v) => {
-                    asns.push(v);
-                    count += 1;
-                }
-                None => {
-                    execute(&asns, db.clone());
-                    break;
-                }
-            };
-        }
-    });
-    for result in reader.deserialize() {
-        let a: ASN4 = result?;
-        let _ = tx.send(Some(a)).await;
-    }
-    let _ = tx.send(None).await;
-    jh.await?;
-    println!("Done!! Took: {:?}", started.elapsed());
-    path.pop();
-
-    // ipv6
-    path.push("GeoLite2-ASN-Blocks-IPv6.csv");
-    let mut reader = csv::Reader::from_path(&path)?;
-    println!("Populating ASN Blocks IPv6");
-    let started = std::time::Instant::now();
-    let (tx, mut rx) = tokio::sync::mpsc::channel::<Option<ASN6>>(10);
-    let db1 = db.clone();
-    // tokio::sync::Pin
-    let jh = tokio::spawn(async move {
-        let mut asns = Vec::<ASN6>::new();
-        let mut count: usize = 0;
-        let batch_size: usize = 100000;
-        let db = db1;
-        fn execute(asns: &[ASN6], db: String) {
-            let mut values = Vec::<String>::new();
-            let mut params = Vec::<&dyn rusqlite::ToSql>::new();
-            let mut q = String::from(
-                "INSERT INTO cities4(
-                    network,autonomous_system_number,autonomous_system_org
-            ) VALUES",
-            );
-            for a in asns.iter() {
-                values.push(String::from("(?,?,?)"));
-                params.push(&a.network);
-                params.push(&a.autonomous_system_number);
-                params.push(&a.autonomous_system_organization);
-            }
-            q = format!("{} {}", q, values.join(","));
-            match execute_query(db, &q, params.as_slice()) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("{:?}", e);
-                }
-            }
-        }
-        while let Some(asn) = rx.recv().await {
-            if count == batch_size {
-                execute(&asns, db.clone());
-                asns = Vec::<ASN6>::new();
-                count = 0;
-            }
-            match asn {
-                Some(v) => {
-                    asns.push(v);
-                    count += 1;
-                }
-                None => {
-                    execute(&asns, db.clone());
-                    break;
-                }
-            };
-        }
-    });
-    for result in reader.deserialize() {
-        let a: ASN6 = result?;
-        let _ = tx.send(Some(a)).await;
     }
-    let _ = tx.send(None).await;
-    jh.await?;
-    println!("Done!! Took: {:?}", started.elapsed());
-    path.pop();
-
-    Ok(())
+    Ok(out)
 }
 
-async fn new_db<P: AsRef<std::path::Path>>(db_dir: P) -> Result<(), GLErr> {
-    let db_dir = db_dir.as_ref();
+// Writes `version.new` and clears leftovers of a failed run, which would
+// otherwise be mixed into this one, except partial downloads, which are
+// resumed. Returns the work directory and the path of the database to build.
+fn prepare_build(
+    config: &GeoliteConfig,
+) -> Result<(std::path::PathBuf, std::path::PathBuf), GLErr> {
+    let db_dir = config.db_dir();
     let version_file = db_dir.join("version.new");
     let mut f = File::create(version_file)?;
     f.write_all(
@@ -905,42 +502,228 @@ async fn new_db<P: AsRef<std::path::Path>>(db_dir: P) -> Result<(), GLErr> {
     )?;
 
     let dbfiles = db_dir.join(std::path::Path::new("dbfiles"));
-    get_db_files(&dbfiles).await?;
-
+    std::fs::create_dir_all(&dbfiles)?;
+    for entry in std::fs::read_dir(&dbfiles)? {
+        let path = entry?.path();
+        if download::is_partial(&path) {
+            continue;
+        }
+        if path.is_dir() {
+            std::fs::remove_dir_all(&path)?;
+        } else {
+            std::fs::remove_file(&path)?;
+        }
+    }
     let dbfile = db_dir.join("geolite2.db.new");
-    let conn = rusqlite::Connection::open(&dbfile)?;
-    let db = if let Ok(s) = dbfile.into_os_string().into_string() {
-        s
-    } else {
-        return Err(GLErr::OSStringErr);
-    };
-    build_tables(&conn)?;
-    countries_from_csv(&dbfiles, db.clone()).await?;
-    cities_from_csv(&dbfiles, db.clone()).await?;
-    asn_from_csv(&dbfiles, db).await?;
+    if dbfile.exists() {
+        std::fs::remove_file(&dbfile)?;
+    }
+    Ok((dbfiles, dbfile))
+}
+
+async fn new_db(config: &GeoliteConfig) -> Result<Sources, GLErr> {
+    let (dbfiles, dbfile) = prepare_build(config)?;
+    let (sources, archives) = get_db_files(config, &dbfiles).await?;
+    import_editions(&archives, &dbfiles, &dbfile, config.error_policy()).await?;
+    Ok(sources)
+}
+
+// Each edition is imported into its own database under `dbfiles` so that
+// the imports run in parallel, and then copied into `dbfile`.
+async fn import_editions(
+    sources: &[(Edition, CsvSource)],
+    dbfiles: &std::path::Path,
+    dbfile: &std::path::Path,
+    policy: ErrorPolicy,
+) -> Result<(), GLErr> {
+    let mut handles = Vec::new();
+    for (edition, source) in sources {
+        let (edition, source) = (*edition, source.clone());
+        let dbfiles = dbfiles.to_path_buf();
+        handles.push(tokio::spawn(async move {
+            let staging = dbfiles.join(format!("{}.db", edition.dir_name()));
+            let bad = dataset::import_edition(edition, &source, &staging, policy).await?;
+            let db = staging
+                .into_os_string()
+                .into_string()
+                .map_err(|_| GLErr::OSStringErr)?;
+            Ok((db, bad))
+        }));
+    }
+    let (staged, bad): (Vec<String>, Vec<Vec<RowError>>) =
+        join_all(handles).await?.into_iter().unzip();
+    let bad: Vec<RowError> = bad.into_iter().flatten().collect();
+    if !bad.is_empty() {
+        for e in bad.iter() {
+            eprintln!("{}", e);
+        }
+        return Err(GLErr::BadRows(bad));
+    }
+
+    let editions: Vec<Edition> = sources.iter().map(|(e, _)| *e).collect();
+    let mut conn = rusqlite::Connection::open(dbfile)?;
+    dataset::tune_for_build(&conn)?;
+    build_tables(&conn, &editions)?;
+    for (edition, staging) in editions.iter().zip(staged) {
+        conn.execute("ATTACH DATABASE ?1 AS staging", [&staging])?;
+        let tx = conn.transaction()?;
+        for table in edition.tables() {
+            tx.execute(
+                &format!("INSERT INTO main.{0} SELECT * FROM staging.{0}", table),
+                (),
+            )?;
+        }
+        tx.commit()?;
+        conn.execute("DETACH DATABASE staging", ())?;
+    }
+    build_indexes(&conn, &editions)?;
     Ok(())
 }
 
-pub async fn update_db() -> Result<(), GLErr> {
-    // Update new version
-    let dbd = std::env::var("GL2_DBDIR")?;
-    let db_dir = std::path::Path::new(&dbd);
-    if !update_needed(db_dir) {
+pub async fn update_db(config: &GeoliteConfig) -> Result<(), GLErr> {
+    if editions_changed(config) {
+        eprintln!("Configured editions differ from the database");
+        return rebuild_db(config).await;
+    }
+    if ranges_missing(&config.db_path()) {
+        eprintln!("The database predates network ranges and has to be rebuilt");
+        return rebuild_db(config).await;
+    }
+    if !update_needed(config) {
         eprintln!("No update needed");
         return Ok(());
     }
-    new_db(db_dir).await?;
+    if !remote_changed(config).await? {
+        eprintln!("Nothing new has been published");
+        let mut sources = Sources::read(config.db_dir());
+        sources.checked = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs();
+        return sources.write(config.db_dir());
+    }
+    rebuild_db(config).await
+}
 
-    // Update file and version names
-    let mut del_old = false;
-    if db_dir.join("version").exists() {
-        std::fs::rename(db_dir.join("version"), db_dir.join("version.old"))?;
-        del_old = true;
+/// Downloads and builds a new database and swaps it in, whether or not one
+/// is due.
+pub async fn rebuild_db(config: &GeoliteConfig) -> Result<(), GLErr> {
+    let sources = new_db(config).await?;
+    let result = swap_in(config, sources.clone());
+    if let Err(GLErr::Anomalies(_)) = result {
+        reject(config, sources)?;
     }
-    std::fs::rename(db_dir.join("version.new"), db_dir.join("version"))?;
-    if del_old {
-        std::fs::remove_file(db_dir.join("version.old"))?;
+    result
+}
+
+// Records the releases of a refused build, so that `update_db` waits for
+// newer ones instead of building the same again.
+fn reject(config: &GeoliteConfig, build: Sources) -> Result<(), GLErr> {
+    let mut sources = Sources::read(config.db_dir());
+    sources.rejected = build.editions;
+    sources.checked = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    sources.write(config.db_dir())
+}
+
+/// Builds a new database from GeoLite2 CSV zip archives or directories they
+/// were extracted to, and swaps it in like `rebuild_db`. Nothing is
+/// downloaded, so no license key is needed. Every configured edition must be
+/// among `paths`; archives of other editions are skipped.
+pub async fn import_db<P: AsRef<std::path::Path>>(
+    config: &GeoliteConfig,
+    paths: &[P],
+) -> Result<(), GLErr> {
+    // `prepare_build` empties `dbfiles`, so nothing in there can be imported
+    let work_dir = config.db_dir().join("dbfiles");
+    let in_work_dir = |path: &std::path::Path| match (path.canonicalize(), work_dir.canonicalize())
+    {
+        (Ok(path), Ok(dir)) => path.starts_with(dir),
+        _ => false,
+    };
+    let mut archives = Vec::<(Edition, CsvSource)>::new();
+    for path in paths {
+        let path = path.as_ref();
+        if in_work_dir(path) {
+            return Err(GLErr::InvalidConfig(format!(
+                "{:?} is inside {:?}, which is emptied before every build; move it elsewhere to import it",
+                path, work_dir
+            )));
+        }
+        let source = CsvSource::new(path, config);
+        let edition = source
+            .edition()?
+            .ok_or_else(|| GLErr::InvalidConfig(format!("no GeoLite2 CSVs in {:?}", path)))?;
+        if !config.editions().contains(&edition) {
+            eprintln!(
+                "Skipping {:?}: the {} edition is not configured",
+                path, edition
+            );
+            continue;
+        }
+        archives.retain(|(e, _)| *e != edition);
+        archives.push((edition, source));
+    }
+    for edition in config.editions() {
+        if !archives.iter().any(|(e, _)| e == edition) {
+            return Err(GLErr::InvalidConfig(format!(
+                "no archive given for the {} edition",
+                edition
+            )));
+        }
     }
+    let (dbfiles, dbfile) = prepare_build(config)?;
+    import_editions(&archives, &dbfiles, &dbfile, config.error_policy()).await?;
+    // Nothing identifies the release, so the next online update rebuilds
+    swap_in(config, Sources::default())
+}
+
+// Keeps a build failing `inspect::check` from replacing the live database.
+fn validate(config: &GeoliteConfig, dbfile: &std::path::Path) -> Result<(), GLErr> {
+    let problems = inspect::check(config, dbfile)?;
+    if problems.is_empty() {
+        return Ok(());
+    }
+    for p in problems.iter() {
+        eprintln!("{}", p);
+    }
+    Err(GLErr::VerificationFailed(problems))
+}
+
+// Compares a build with the live database, if there is one it can be
+// compared with, writes what moved to `anomalies.json` and refuses the build
+// if configured to.
+fn compare_with_live(config: &GeoliteConfig, dbfile: &std::path::Path) -> Result<(), GLErr> {
+    let live = config.db_path();
+    if config.anomaly_action() == AnomalyAction::Off || !live.exists() || ranges_missing(&live) {
+        return Ok(());
+    }
+    let report = anomaly::compare(config, &live, dbfile)?;
+    std::fs::write(
+        config.db_dir().join("anomalies.json"),
+        serde_json::to_string_pretty(&report)?,
+    )?;
+    let anomalies = report.anomalies();
+    if anomalies.is_empty() {
+        return Ok(());
+    }
+    for a in anomalies.iter() {
+        eprintln!("{}", a);
+    }
+    if config.anomaly_action() == AnomalyAction::Refuse {
+        return Err(GLErr::Anomalies(anomalies));
+    }
+    Ok(())
+}
+
+// Renames the freshly built database and version into place.
+fn swap_in(config: &GeoliteConfig, mut sources: Sources) -> Result<(), GLErr> {
+    let db_dir = config.db_dir();
+    validate(config, &db_dir.join("geolite2.db.new"))?;
+    compare_with_live(config, &db_dir.join("geolite2.db.new"))?;
+
+    // Update file and version names. The database goes first so that a
+    // reader watching `version` never sees it change ahead of the data.
     let mut del_old = false;
     if db_dir.join("geolite2.db").exists() {
         std::fs::rename(db_dir.join("geolite2.db"), db_dir.join("geolite2.db.old"))?;
@@ -950,15 +733,206 @@ pub async fn update_db() -> Result<(), GLErr> {
     if del_old {
         std::fs::remove_file(db_dir.join("geolite2.db.old"))?;
     }
+    let mut del_old = false;
+    if db_dir.join("version").exists() {
+        std::fs::rename(db_dir.join("version"), db_dir.join("version.old"))?;
+        del_old = true;
+    }
+    std::fs::rename(db_dir.join("version.new"), db_dir.join("version"))?;
+    if del_old {
+        std::fs::remove_file(db_dir.join("version.old"))?;
+    }
+    sources.checked = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    sources.write(db_dir)?;
     if db_dir.join("dbfiles").exists() {
         std::fs::remove_dir_all(db_dir.join("dbfiles"))?;
     }
     Ok(())
 }
 
-pub fn phone_codes() -> Result<HashMap<String, String>, GLErr> {
-    let var = std::env::var("PHONE_JSON_FILE")?;
-    let json_string = std::fs::read_to_string(&var)?;
+pub fn phone_codes(config: &GeoliteConfig) -> Result<HashMap<String, String>, GLErr> {
+    let path = config
+        .phone_file()
+        .ok_or_else(|| GLErr::InvalidConfig(String::from("phone_file is not set")))?;
+    let json_string = std::fs::read_to_string(path)?;
     let h: HashMap<String, String> = serde_json::from_str(&json_string)?;
     Ok(h)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_check_keeps_the_live_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let config = GeoliteConfig::builder().db_dir(dir).build().unwrap();
+        std::fs::write(dir.join("geolite2.db"), "live").unwrap();
+        std::fs::write(dir.join("version"), "1").unwrap();
+        // A build whose tables are all empty
+        let conn = rusqlite::Connection::open(dir.join("geolite2.db.new")).unwrap();
+        build_tables(&conn, &Edition::ALL).unwrap();
+        drop(conn);
+        std::fs::write(dir.join("version.new"), "2").unwrap();
+
+        match swap_in(&config, Sources::default()) {
+            Err(GLErr::VerificationFailed(problems)) => {
+                assert!(problems.contains(&String::from("table asn4 is empty")))
+            }
+            r => panic!("expected a failed verification, got {:?}", r),
+        }
+        assert_eq!(
+            std::fs::read_to_string(dir.join("geolite2.db")).unwrap(),
+            "live"
+        );
+        assert_eq!(std::fs::read_to_string(dir.join("version")).unwrap(), "1");
+    }
+
+    #[test]
+    fn sha256_companions() {
+        let digest = "49d2b3c9b1a6e0fd0a54e9ff5e2b2c0c3ea3d4ae6b1e0a7b3f0f3e5d8a4c2b1f";
+        assert_eq!(
+            parse_sha256(
+                Edition::Asn,
+                &format!("{}  GeoLite2-ASN-CSV_20221018.zip\n", digest)
+            )
+            .unwrap(),
+            digest
+        );
+        assert_eq!(
+            parse_sha256(Edition::Asn, &digest.to_ascii_uppercase()).unwrap(),
+            digest
+        );
+        for body in [
+            "",
+            "  \n",
+            "<!doctype html><html>Not found</html>",
+            &digest[1..],
+            &format!("{}0", digest),
+            &digest.replace('a', "g"),
+        ] {
+            match parse_sha256(Edition::City, body) {
+                Err(GLErr::MalformedChecksum { edition, .. }) => assert_eq!(edition, Edition::City),
+                r => panic!("{:?} was accepted: {:?}", body, r),
+            }
+        }
+    }
+
+    // Answers every request with `archive` or, for the checksum, `sha256`.
+    fn serve(archive: &'static [u8], sha256: &'static str) -> String {
+        use std::io::BufRead;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = String::new();
+                std::io::BufReader::new(&stream)
+                    .read_line(&mut request)
+                    .unwrap();
+                let body = match request.contains("suffix=zip.sha256") {
+                    true => sha256.as_bytes(),
+                    false => archive,
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn archives_failing_their_checksum_are_not_imported() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let sha256 = "0000000000000000000000000000000000000000000000000000000000000000  x.zip";
+        let config = GeoliteConfig::builder()
+            .db_dir(dir)
+            .editions(&[Edition::Asn])
+            .license_key("KEY")
+            .base_url(serve(b"not the real archive", sha256))
+            .build()
+            .unwrap();
+
+        match rebuild_db(&config).await {
+            Err(GLErr::ChecksumMismatch {
+                edition,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(edition, Edition::Asn);
+                assert_eq!(expected, &sha256[..64]);
+                assert_eq!(actual, sha256_file(dir.join("dbfiles/asn.zip")).unwrap());
+            }
+            r => panic!("expected a checksum mismatch, got {:?}", r),
+        }
+        assert!(!dir.join("geolite2.db").exists());
+        assert!(!dir.join("geolite2.db.new").exists());
+        assert!(!dir.join("dbfiles/asn.db").exists());
+    }
+
+    #[tokio::test]
+    async fn archives_in_the_work_dir_are_not_imported() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let config = GeoliteConfig::builder().db_dir(dir).build().unwrap();
+        // Left behind by a failed update
+        let dbfiles = dir.join("dbfiles");
+        std::fs::create_dir_all(dbfiles.join("GeoLite2-ASN-CSV_20221018")).unwrap();
+        std::fs::write(dbfiles.join("GeoLite2-ASN-CSV.zip"), "zip").unwrap();
+
+        for path in [
+            dbfiles.join("GeoLite2-ASN-CSV.zip"),
+            dbfiles.join("GeoLite2-ASN-CSV_20221018"),
+            dir.join("dbfiles/../dbfiles"),
+        ] {
+            match import_db(&config, &[&path]).await {
+                Err(GLErr::InvalidConfig(e)) => assert!(e.contains("dbfiles"), "{}", e),
+                r => panic!("expected {:?} to be refused, got {:?}", path, r),
+            }
+        }
+        assert!(dbfiles.join("GeoLite2-ASN-CSV.zip").exists());
+        assert!(dbfiles.join("GeoLite2-ASN-CSV_20221018").exists());
+        assert!(!dir.join("version.new").exists());
+    }
+
+    #[test]
+    fn databases_without_ranges_are_detected() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let current = dir.join("current.db");
+        build_tables(
+            &rusqlite::Connection::open(&current).unwrap(),
+            &Edition::ALL,
+        )
+        .unwrap();
+        assert!(!ranges_missing(&current));
+        assert!(!ranges_missing(&dir.join("missing.db")));
+
+        // asn4 as it was created before the range columns
+        let old = dir.join("old.db");
+        let conn = rusqlite::Connection::open(&old).unwrap();
+        build_tables(&conn, &[Edition::Country]).unwrap();
+        build_table_asn6(&conn).unwrap();
+        conn.execute(
+            "CREATE TABLE asn4 (
+            network TEXT PRIMARY KEY,
+            autonomous_system_number INT,
+            autonomous_system_org TEXT)",
+            (),
+        )
+        .unwrap();
+        drop(conn);
+        assert!(ranges_missing(&old));
+    }
+}
@@ -0,0 +1,406 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use reqwest::{header, RequestBuilder, StatusCode};
+
+use crate::config::Edition;
+use crate::errors::GLErr;
+use crate::sources::RemoteMeta;
+
+const MAX_ATTEMPTS: u32 = 5;
+const MAX_WAIT: Duration = Duration::from_secs(300);
+
+/// How far the download of one edition's archive has got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    pub edition: Edition,
+    pub downloaded: u64,
+    /// `None` when the server did not say how large the archive is.
+    pub total: Option<u64>,
+}
+
+/// Called every time a chunk of an archive has been written.
+#[derive(Clone)]
+pub struct ProgressFn(Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl ProgressFn {
+    pub fn new<F: Fn(&Progress) + Send + Sync + 'static>(f: F) -> ProgressFn {
+        ProgressFn(Arc::new(f))
+    }
+}
+
+impl std::fmt::Debug for ProgressFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressFn")
+    }
+}
+
+// Why an attempt failed and whether another one may succeed.
+struct Failed {
+    err: GLErr,
+    retry: bool,
+    after: Option<Duration>,
+}
+
+impl Failed {
+    fn fatal<E: Into<GLErr>>(e: E) -> Failed {
+        Failed {
+            err: e.into(),
+            retry: false,
+            after: None,
+        }
+    }
+
+    fn retry<E: Into<GLErr>>(e: E) -> Failed {
+        Failed {
+            err: e.into(),
+            retry: true,
+            after: None,
+        }
+    }
+}
+
+/// Streams `req` into `path`. Bytes go to `path.part` first, and a failed
+/// attempt is retried with exponential backoff (or after the server's
+/// `Retry-After`), resuming from where the part file ends. A part file left
+/// by an earlier run is resumed as well, as long as the release it belongs
+/// to is still the one being served.
+pub(crate) async fn download(
+    req: RequestBuilder,
+    path: &Path,
+    edition: Edition,
+    progress: Option<&ProgressFn>,
+) -> Result<RemoteMeta, GLErr> {
+    println!("Downloading to: {:?}", path);
+    let part = part_path(path);
+    let mut validator = std::fs::read_to_string(validator_path(&part))
+        .ok()
+        .filter(|v| !v.is_empty());
+    // Without knowing which release it holds it cannot be resumed safely
+    if validator.is_none() && part.exists() {
+        std::fs::remove_file(&part)?;
+    }
+    let mut attempt = 1;
+    loop {
+        match fetch(&req, &part, edition, progress, &mut validator).await {
+            Ok(meta) => {
+                std::fs::rename(&part, path)?;
+                let _ = std::fs::remove_file(validator_path(&part));
+                return Ok(meta);
+            }
+            Err(f) if f.retry && attempt < MAX_ATTEMPTS => {
+                let wait = f
+                    .after
+                    .unwrap_or_else(|| Duration::from_secs(1 << (attempt - 1)))
+                    .min(MAX_WAIT);
+                eprintln!(
+                    "{} download attempt {} failed, retrying in {:?}: {:?}",
+                    edition, attempt, wait, f.err
+                );
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+            Err(f) => return Err(f.err),
+        }
+    }
+}
+
+async fn fetch(
+    req: &RequestBuilder,
+    part: &Path,
+    edition: Edition,
+    progress: Option<&ProgressFn>,
+    validator: &mut Option<String>,
+) -> Result<RemoteMeta, Failed> {
+    let have = std::fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+    let mut req = req
+        .try_clone()
+        .expect("download requests have no streaming body");
+    if have > 0 {
+        req = req.header(header::RANGE, format!("bytes={}-", have));
+        // Resume only the same release, otherwise the server sends all of it
+        if let Some(v) = validator.as_deref() {
+            req = req.header(header::IF_RANGE, v);
+        }
+    }
+    let mut res = req.send().await.map_err(Failed::retry)?;
+    let status = res.status();
+    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+        let after = retry_after(res.headers());
+        let err = res.error_for_status().expect_err("error status");
+        return Err(Failed {
+            err: err.into(),
+            retry: true,
+            after,
+        });
+    }
+    if status == StatusCode::RANGE_NOT_SATISFIABLE {
+        // The part file is no use, start over
+        std::fs::remove_file(part).map_err(Failed::fatal)?;
+        let err = res.error_for_status().expect_err("error status");
+        return Err(Failed::retry(err));
+    }
+    res = res.error_for_status().map_err(Failed::fatal)?;
+    let meta = RemoteMeta::from_headers(res.headers());
+    *validator = meta.etag.clone().or_else(|| meta.last_modified.clone());
+    match validator.as_deref() {
+        Some(v) => std::fs::write(validator_path(part), v).map_err(Failed::fatal)?,
+        None => {
+            let _ = std::fs::remove_file(validator_path(part));
+        }
+    }
+
+    let (mut written, total) = if status == StatusCode::PARTIAL_CONTENT {
+        match content_range(res.headers()) {
+            Some((start, total)) if start == have => (have, total),
+            range => {
+                std::fs::remove_file(part).map_err(Failed::fatal)?;
+                return Err(Failed::retry(GLErr::IncompleteDownload {
+                    edition,
+                    expected: range.and_then(|(_, t)| t).unwrap_or_default(),
+                    received: have,
+                }));
+            }
+        }
+    } else {
+        (0, res.content_length())
+    };
+    let mut f = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(written > 0)
+        .truncate(written == 0)
+        .open(part)
+        .map_err(Failed::fatal)?;
+    while let Some(chunk) = res.chunk().await.map_err(Failed::retry)? {
+        f.write_all(&chunk).map_err(Failed::fatal)?;
+        written += chunk.len() as u64;
+        if let Some(p) = progress {
+            (p.0)(&Progress {
+                edition,
+                downloaded: written,
+                total,
+            });
+        }
+    }
+    match total {
+        Some(t) if t != written => Err(Failed::retry(GLErr::IncompleteDownload {
+            edition,
+            expected: t,
+            received: written,
+        })),
+        _ => Ok(meta),
+    }
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".part");
+    PathBuf::from(p)
+}
+
+// The `ETag` or `Last-Modified` of the release a part file holds, sent as
+// `If-Range` when resuming it.
+fn validator_path(part: &Path) -> PathBuf {
+    let mut p = part.as_os_str().to_owned();
+    p.push(".validator");
+    PathBuf::from(p)
+}
+
+/// Whether `path` is a partial download, or belongs to one, that a later
+/// run may resume.
+pub(crate) fn is_partial(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.ends_with(".part") || name.ends_with(".part.validator")
+}
+
+// Only the delay-seconds form; an HTTP date falls back to the backoff.
+fn retry_after(headers: &header::HeaderMap) -> Option<Duration> {
+    headers
+        .get(header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+// (first byte, full length) of a `Content-Range: bytes 100-199/200` header.
+fn content_range(headers: &header::HeaderMap) -> Option<(u64, Option<u64>)> {
+    let v = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let (range, total) = v.strip_prefix("bytes ")?.split_once('/')?;
+    let (start, _) = range.split_once('-')?;
+    Some((start.parse().ok()?, total.parse().ok()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    use super::*;
+
+    const BODY: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> header::HeaderMap {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.clone(), v.parse().unwrap()))
+            .collect()
+    }
+
+    // Serves BODY with `etag`, honouring `Range` unless an `If-Range` names
+    // another release, and sends back the request headers it got.
+    fn serve(etag: &'static str) -> (String, mpsc::Receiver<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://{}/GeoLite2-ASN-CSV.zip",
+            listener.local_addr().unwrap()
+        );
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let lines: Vec<String> = BufReader::new(&stream)
+                    .lines()
+                    .map(|l| l.unwrap().to_ascii_lowercase())
+                    .take_while(|l| !l.is_empty())
+                    .collect();
+                let value = |name: &str| {
+                    lines
+                        .iter()
+                        .find_map(|l| l.strip_prefix(&format!("{}: ", name)))
+                        .map(String::from)
+                };
+                let start = value("range")
+                    .filter(|_| value("if-range").is_none_or(|v| v == etag))
+                    .map(|r| r["bytes=".len()..r.len() - 1].parse::<usize>().unwrap());
+                let head = match start {
+                    Some(s) => format!(
+                        "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n",
+                        s,
+                        BODY.len() - 1,
+                        BODY.len()
+                    ),
+                    None => String::from("HTTP/1.1 200 OK\r\n"),
+                };
+                let body = &BODY[start.unwrap_or(0)..];
+                write!(
+                    stream,
+                    "{}ETag: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    head,
+                    etag,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+                tx.send(lines).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    // Downloads into a dir holding `part` and `validator`, if given, and
+    // returns the request headers sent.
+    async fn resume(part: Option<&[u8]>, validator: Option<&str>) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("GeoLite2-ASN-CSV.zip");
+        if let Some(p) = part {
+            std::fs::write(part_path(&path), p).unwrap();
+        }
+        if let Some(v) = validator {
+            std::fs::write(validator_path(&part_path(&path)), v).unwrap();
+        }
+        let (url, rx) = serve("\"v1\"");
+        let req = reqwest::Client::new().get(url);
+        let meta = download(req, &path, Edition::Asn, None).await.unwrap();
+        assert_eq!(meta.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(std::fs::read(&path).unwrap(), BODY);
+        assert!(!part_path(&path).exists());
+        assert!(!validator_path(&part_path(&path)).exists());
+        rx.recv().unwrap()
+    }
+
+    #[tokio::test]
+    async fn part_files_are_resumed_only_for_the_same_release() {
+        let sent = resume(Some(&BODY[..10]), Some("\"v1\"")).await;
+        assert!(
+            sent.contains(&String::from("range: bytes=10-")),
+            "{:?}",
+            sent
+        );
+        assert!(
+            sent.contains(&String::from("if-range: \"v1\"")),
+            "{:?}",
+            sent
+        );
+
+        // The server sends the whole new release instead
+        let sent = resume(Some(b"old release"), Some("\"v0\"")).await;
+        assert!(
+            sent.contains(&String::from("if-range: \"v0\"")),
+            "{:?}",
+            sent
+        );
+
+        // Without a validator the part file is dropped
+        let sent = resume(Some(b"unknown"), None).await;
+        assert!(!sent.iter().any(|l| l.starts_with("range:")), "{:?}", sent);
+
+        let sent = resume(None, None).await;
+        assert!(!sent.iter().any(|l| l.starts_with("range:")), "{:?}", sent);
+    }
+
+    #[test]
+    fn content_ranges() {
+        let range = |v: &str| content_range(&headers(&[(header::CONTENT_RANGE, v)]));
+        assert_eq!(range("bytes 100-199/200"), Some((100, Some(200))));
+        assert_eq!(range("bytes 0-0/1"), Some((0, Some(1))));
+        assert_eq!(range("bytes 100-199/*"), Some((100, None)));
+        assert_eq!(range("bytes */200"), None);
+        assert_eq!(range("items 100-199/200"), None);
+        assert_eq!(range("bytes 100/200"), None);
+        assert_eq!(range("bytes x-199/200"), None);
+        assert_eq!(content_range(&headers(&[])), None);
+    }
+
+    #[test]
+    fn retry_afters() {
+        let after = |v: &str| retry_after(&headers(&[(header::RETRY_AFTER, v)]));
+        assert_eq!(after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(after(" 0 "), Some(Duration::ZERO));
+        assert_eq!(after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+        assert_eq!(after("-1"), None);
+        assert_eq!(retry_after(&headers(&[])), None);
+    }
+
+    #[test]
+    fn partial_files() {
+        for (name, partial) in [
+            ("GeoLite2-ASN-CSV.zip.part", true),
+            ("GeoLite2-ASN-CSV.zip.part.validator", true),
+            ("GeoLite2-ASN-CSV.zip", false),
+            ("GeoLite2-ASN-CSV.zip.sha256", false),
+            ("GeoLite2-ASN-CSV_20221018", false),
+            ("asn.db", false),
+            ("part", false),
+        ] {
+            assert_eq!(
+                is_partial(&Path::new("dbfiles").join(name)),
+                partial,
+                "{}",
+                name
+            );
+        }
+        let part = part_path(Path::new("dbfiles/GeoLite2-ASN-CSV.zip"));
+        assert!(is_partial(&part));
+        assert!(is_partial(&validator_path(&part)));
+    }
+}
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::config::Edition;
+
 #[derive(Debug, Error)]
 pub enum GLErr {
     #[error("sqlite error")]
@@ -10,10 +12,8 @@ pub enum GLErr {
     TimeErr(#[from] std::time::SystemTimeError),
     #[error("parse int error")]
     ParseIntErr(#[from] std::num::ParseIntError),
-    #[error("env var not found")]
-    MissingEnvVar(#[from] std::env::VarError),
     #[error("reqwest error")]
-    ReqwestErr(#[from] reqwest::Error),
+    ReqwestErr(reqwest::Error),
     #[error("zip error")]
     ZipErr(#[from] zip::result::ZipError),
     #[error("csv errored")]
@@ -22,10 +22,76 @@ pub enum GLErr {
     TaskJoinErr(#[from] tokio::task::JoinError),
     #[error("serde-json error")]
     SerdeJSONErr(#[from] serde_json::Error),
-    #[error("dir not found. zip extraction may have failed")]
-    ZipExtractErr,
-    #[error("sqlite cursor.next failed")]
-    CursorNextErr,
     #[error("returned osstring")]
     OSStringErr,
+    #[error("invalid network: {0}")]
+    InvalidNetwork(String),
+    #[error("invalid schedule: {0}")]
+    InvalidSchedule(String),
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("verification failed: {0:?}")]
+    VerificationFailed(Vec<String>),
+    #[error("the new build differs too much from the live database: {0:?}")]
+    Anomalies(Vec<String>),
+    #[error("{edition} archive checksum mismatch: expected {expected}, got {actual}")]
+    ChecksumMismatch {
+        edition: Edition,
+        expected: String,
+        actual: String,
+    },
+    #[error("{edition} checksum file holds no SHA-256 digest: {body:?}")]
+    MalformedChecksum { edition: Edition, body: String },
+    #[error("{edition} download ended after {received} of {expected} bytes")]
+    IncompleteDownload {
+        edition: Edition,
+        expected: u64,
+        received: u64,
+    },
+    #[error("the database was built without the {0} edition")]
+    EditionNotBuilt(Edition),
+    #[error("{0} not found")]
+    CsvNotFound(String),
+    #[error("unsafe archive: {0}")]
+    UnsafeArchive(String),
+    #[error("bad row: {0}")]
+    BadRow(RowError),
+    #[error("more than {max} bad rows in {table}, the last: {last}")]
+    TooManyBadRows {
+        table: &'static str,
+        max: usize,
+        last: RowError,
+    },
+    #[error("{} bad rows", .0.len())]
+    BadRows(Vec<RowError>),
+    #[error("toml error")]
+    TomlErr(#[from] toml::de::Error),
+}
+
+// Without the URL: the legacy download URLs carry the license key, and
+// these errors get logged.
+impl From<reqwest::Error> for GLErr {
+    fn from(e: reqwest::Error) -> Self {
+        GLErr::ReqwestErr(e.without_url())
+    }
+}
+
+/// A CSV row that could not be imported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowError {
+    /// The CSV file, or `archive.zip:entry` when read from an archive.
+    pub path: String,
+    pub line: u64,
+    pub table: &'static str,
+    pub reason: String,
+}
+
+impl std::fmt::Display for RowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} line {} ({}): {}",
+            self.path, self.line, self.table, self.reason
+        )
+    }
 }
@@ -0,0 +1,205 @@
+use std::{io::Write, path::Path};
+
+use serde::Serialize;
+
+use crate::config::{Edition, GeoliteConfig};
+use crate::db;
+use crate::errors::GLErr;
+use crate::reader::GeoDb;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TableInfo {
+    pub name: String,
+    pub rows: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DbInfo {
+    pub path: String,
+    /// Seconds since the epoch, from the `version` file.
+    pub built_at: Option<u64>,
+    pub next_update: u64,
+    pub tables: Vec<TableInfo>,
+}
+
+pub(crate) fn open(path: &Path) -> Result<rusqlite::Connection, GLErr> {
+    Ok(rusqlite::Connection::open_with_flags(
+        path,
+        rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?)
+}
+
+fn epoch_secs(t: std::time::SystemTime) -> u64 {
+    t.duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Tables of the editions the database holds.
+fn built_tables(conn: &rusqlite::Connection) -> Result<Vec<&'static str>, GLErr> {
+    Ok(db::built_editions(conn)?
+        .iter()
+        .flat_map(|e| e.tables())
+        .copied()
+        .collect())
+}
+
+/// Build time, next due time and row counts of the configured database.
+pub fn info(config: &GeoliteConfig) -> Result<DbInfo, GLErr> {
+    let conn = open(&config.db_path())?;
+    let mut tables = Vec::<TableInfo>::new();
+    for name in built_tables(&conn)? {
+        let rows = conn.query_row(&format!("SELECT COUNT(*) FROM {}", name), [], |r| r.get(0))?;
+        tables.push(TableInfo {
+            name: name.to_string(),
+            rows,
+        });
+    }
+    Ok(DbInfo {
+        path: config.db_path().display().to_string(),
+        built_at: db::last_update(config).map(epoch_secs),
+        next_update: epoch_secs(db::next_update(config)),
+        tables,
+    })
+}
+
+/// Runs `check` against the configured database.
+pub fn verify(config: &GeoliteConfig) -> Result<Vec<String>, GLErr> {
+    check(config, &config.db_path())
+}
+
+/// Runs SQLite's integrity check on the database at `path`, compares its row
+/// counts with `min_rows`, looks for blocks whose `geoname_id` has no
+/// location row and resolves the golden IPs. Returns the problems found; an
+/// empty list means the database looks usable.
+pub fn check(config: &GeoliteConfig, path: &Path) -> Result<Vec<String>, GLErr> {
+    let conn = open(path)?;
+    let mut problems = Vec::<String>::new();
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let mut rows = stmt.query([])?;
+    while let Some(r) = rows.next()? {
+        let msg: String = r.get(0)?;
+        if msg != "ok" {
+            problems.push(msg);
+        }
+    }
+    let tables = built_tables(&conn)?;
+    for name in tables.iter() {
+        let rows: u64 =
+            conn.query_row(&format!("SELECT COUNT(*) FROM {}", name), [], |r| r.get(0))?;
+        let min = config.min_rows(name);
+        if rows == 0 && min > 0 {
+            problems.push(format!("table {} is empty", name));
+        } else if rows < min {
+            problems.push(format!(
+                "table {} has {} rows, fewer than {}",
+                name, rows, min
+            ));
+        }
+    }
+    for (blocks, locations) in [
+        ("countries4", "countries"),
+        ("countries6", "countries"),
+        ("cities4", "cities"),
+        ("cities6", "cities"),
+    ] {
+        if !tables.contains(&blocks) {
+            continue;
+        }
+        let orphans: i64 = conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM {} b WHERE b.geoname_id IS NOT NULL
+                AND NOT EXISTS (SELECT 1 FROM {} l WHERE l.geoname_id = b.geoname_id)",
+                blocks, locations
+            ),
+            [],
+            |r| r.get(0),
+        )?;
+        if orphans > 0 {
+            problems.push(format!(
+                "{} rows of {} have a geoname_id missing from {}",
+                orphans, blocks, locations
+            ));
+        }
+    }
+    if !config.golden_ips().is_empty() {
+        problems.extend(check_golden_ips(config, path)?);
+    }
+    Ok(problems)
+}
+
+fn check_golden_ips(config: &GeoliteConfig, path: &Path) -> Result<Vec<String>, GLErr> {
+    let db = GeoDb::open(path)?;
+    if !db.editions().contains(&Edition::Country) && !db.editions().contains(&Edition::City) {
+        return Ok(vec![String::from(
+            "golden IPs need the Country or City edition",
+        )]);
+    }
+    let mut problems = Vec::<String>::new();
+    for golden in config.golden_ips() {
+        let rec = db.lookup(golden.ip)?;
+        let country = rec
+            .country
+            .map(|c| c.country_iso_code)
+            .or_else(|| rec.city.map(|c| c.country_iso_code))
+            .filter(|c| !c.is_empty());
+        if country.as_deref() != Some(golden.country.as_str()) {
+            problems.push(format!(
+                "{} resolves to {}, expected {}",
+                golden.ip,
+                country.as_deref().unwrap_or("no country"),
+                golden.country
+            ));
+        }
+    }
+    Ok(problems)
+}
+
+/// Writes `table` as CSV, headed by the table's own column names (e.g.
+/// `is_in_eu`, not the GeoLite2 CSVs' `is_in_european_union`) and leaving
+/// out the derived `network_start` and `network_end`.
+pub fn export_table<W: Write>(config: &GeoliteConfig, table: &str, out: W) -> Result<(), GLErr> {
+    if !db::TABLES.contains(&table) {
+        return Err(GLErr::InvalidConfig(format!("unknown table {}", table)));
+    }
+    let conn = open(&config.db_path())?;
+    if !built_tables(&conn)?.contains(&table) {
+        return Err(GLErr::InvalidConfig(format!(
+            "table {} is not in the database",
+            table
+        )));
+    }
+    let mut columns = Vec::<String>::new();
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let mut rows = stmt.query([])?;
+    while let Some(r) = rows.next()? {
+        let name: String = r.get(1)?;
+        // Derived from `network`, and blobs for IPv6
+        if name != "network_start" && name != "network_end" {
+            columns.push(name);
+        }
+    }
+
+    let mut w = csv::Writer::from_writer(out);
+    w.write_record(&columns)?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM {}", columns.join(","), table))?;
+    let mut rows = stmt.query([])?;
+    let mut record = Vec::<String>::with_capacity(columns.len());
+    while let Some(r) = rows.next()? {
+        record.clear();
+        for i in 0..columns.len() {
+            record.push(match r.get_ref(i)? {
+                rusqlite::types::ValueRef::Null => String::new(),
+                rusqlite::types::ValueRef::Integer(v) => v.to_string(),
+                rusqlite::types::ValueRef::Real(v) => v.to_string(),
+                rusqlite::types::ValueRef::Text(v) => String::from_utf8_lossy(v).into_owned(),
+                rusqlite::types::ValueRef::Blob(v) => {
+                    v.iter().map(|b| format!("{:02x}", b)).collect()
+                }
+            });
+        }
+        w.write_record(&record)?;
+    }
+    w.flush()?;
+    Ok(())
+}
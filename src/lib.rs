@@ -1,2 +1,15 @@
+pub mod anomaly;
+mod archive;
+pub mod config;
+mod dataset;
 pub mod db;
+pub mod download;
 pub mod errors;
+pub mod inspect;
+pub mod memory;
+pub mod network;
+pub mod reader;
+pub mod schedule;
+pub mod shared;
+pub mod sources;
+pub mod updater;
@@ -1,9 +1,247 @@
+use std::{collections::HashMap, net::IpAddr, path::PathBuf, sync::Mutex};
+
+use clap::{Parser, Subcommand, ValueEnum};
+use geolite::config::{Edition, GeoliteConfig, GeoliteConfigBuilder};
 use geolite::db;
+use geolite::download::Progress;
 use geolite::errors::GLErr;
+use geolite::inspect;
+use geolite::reader::GeoDb;
+use geolite::schedule::format_utc;
+use geolite::updater::Updater;
+
+#[derive(Parser)]
+#[command(
+    name = "geolite",
+    version,
+    about = "Build and query a GeoLite2 SQLite database"
+)]
+struct Cli {
+    /// Output format
+    #[arg(long, value_enum, default_value_t = Format::Table, global = true)]
+    format: Format,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Rebuild the database if it is due (the default command)
+    Update {
+        /// Rebuild even if the current database is not due yet
+        #[arg(long)]
+        force: bool,
+    },
+    /// Keep running and rebuild whenever the database is due
+    Daemon,
+    /// Build the database from local GeoLite2 CSV zips or extracted directories
+    Import {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// Look up country, city and ASN of one or more addresses
+    Lookup {
+        #[arg(required = true)]
+        ips: Vec<IpAddr>,
+    },
+    /// Show when the database was built and how many rows each table holds
+    Info,
+    /// Run the checks a new build must pass against the database
+    Verify,
+    /// Write a table as CSV; `--format json` is refused
+    Export {
+        table: String,
+        /// File to write instead of stdout
+        #[arg(long, short)]
+        output: Option<PathBuf>,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<(), GLErr> {
-    let _pcodes = db::phone_codes()?;
-    db::update_db().await?;
+    let cli = Cli::parse();
+    // A TOML file named by GL2_CONFIG, with environment variables on top
+    let mut builder = match std::env::var("GL2_CONFIG") {
+        Ok(p) => GeoliteConfigBuilder::from_toml_file(p)?.env()?,
+        Err(_) => GeoliteConfigBuilder::default().env()?,
+    };
+    let command = cli.command.unwrap_or(Command::Update { force: false });
+    if matches!(command, Command::Update { .. }) {
+        let editions = builder.clone().build()?.editions().len();
+        builder = builder.on_progress(progress_bar(editions));
+    }
+    let config = builder.build()?;
+    match command {
+        Command::Update { force: false } => db::update_db(&config).await?,
+        Command::Update { force: true } => db::rebuild_db(&config).await?,
+        Command::Daemon => Updater::new(config).run().await,
+        Command::Import { paths } => db::import_db(&config, &paths).await?,
+        Command::Lookup { ips } => lookup(&config, &ips, cli.format)?,
+        Command::Info => info(&config, cli.format)?,
+        Command::Verify => verify(&config, cli.format)?,
+        Command::Export { .. } if cli.format == Format::Json => {
+            return Err(GLErr::InvalidConfig(
+                "export only writes CSV, drop --format json".to_string(),
+            ))
+        }
+        Command::Export { table, output } => match output {
+            Some(p) => inspect::export_table(&config, &table, std::fs::File::create(p)?)?,
+            None => inspect::export_table(&config, &table, std::io::stdout().lock())?,
+        },
+    }
+    Ok(())
+}
+
+// One line on stderr covering every edition, redrawn whenever one of the
+// percentages changes and ended once all `editions` are complete.
+fn progress_bar(editions: usize) -> impl Fn(&Progress) + Send + Sync {
+    let shown = Mutex::new(HashMap::<Edition, Progress>::new());
+    move |p| {
+        let step = |p: &Progress| match p.total {
+            Some(t) if t > 0 => p.downloaded * 100 / t,
+            _ => p.downloaded >> 20,
+        };
+        let mut shown = shown.lock().unwrap();
+        if shown.insert(p.edition, *p).map(|old| step(&old)) == Some(step(p)) {
+            return;
+        }
+        let line: Vec<String> = Edition::ALL
+            .iter()
+            .filter_map(|e| shown.get(e))
+            .map(|p| match p.total {
+                Some(_) => format!("{} {:>3}%", p.edition, step(p)),
+                None => format!("{} {:.1} MB", p.edition, p.downloaded as f64 / 1e6),
+            })
+            .collect();
+        eprint!("\r{}", line.join("  "));
+        if shown.len() == editions && shown.values().all(|p| p.total == Some(p.downloaded)) {
+            eprintln!();
+        }
+    }
+}
+
+fn lookup(config: &GeoliteConfig, ips: &[IpAddr], format: Format) -> Result<(), GLErr> {
+    let db = GeoDb::open(config.db_path())?;
+    let mut records = Vec::new();
+    for ip in ips {
+        records.push((ip, db.lookup(*ip)?));
+    }
+    if format == Format::Json {
+        let out: Vec<serde_json::Value> = records
+            .iter()
+            .map(|(ip, rec)| serde_json::json!({ "ip": ip, "country": rec.country, "city": rec.city, "asn": rec.asn }))
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&out)?);
+        return Ok(());
+    }
+    let rows = records
+        .iter()
+        .map(|(ip, rec)| {
+            let opt = |v: Option<String>| v.unwrap_or_default();
+            vec![
+                ip.to_string(),
+                opt(rec.country.as_ref().map(|c| c.country_iso_code.clone())),
+                opt(rec.city.as_ref().map(|c| c.subdivision_1_name.clone())),
+                opt(rec.city.as_ref().map(|c| c.city_name.clone())),
+                opt(rec.city.as_ref().map(|c| c.postal_code.clone())),
+                opt(rec
+                    .city
+                    .as_ref()
+                    .and_then(|c| c.latitude)
+                    .map(|v| v.to_string())),
+                opt(rec
+                    .city
+                    .as_ref()
+                    .and_then(|c| c.longitude)
+                    .map(|v| v.to_string())),
+                opt(rec
+                    .asn
+                    .as_ref()
+                    .map(|a| a.autonomous_system_number.to_string())),
+                opt(rec.asn.as_ref().map(|a| a.autonomous_system_org.clone())),
+            ]
+        })
+        .collect::<Vec<Vec<String>>>();
+    print_table(
+        &[
+            "ip",
+            "country",
+            "subdivision",
+            "city",
+            "postal",
+            "lat",
+            "lon",
+            "asn",
+            "org",
+        ],
+        &rows,
+    );
+    Ok(())
+}
+
+fn info(config: &GeoliteConfig, format: Format) -> Result<(), GLErr> {
+    let info = inspect::info(config)?;
+    if format == Format::Json {
+        println!("{}", serde_json::to_string_pretty(&info)?);
+        return Ok(());
+    }
+    println!("database:    {}", info.path);
+    match info.built_at {
+        Some(t) => println!("built at:    {}", format_utc(t)),
+        None => println!("built at:    unknown"),
+    }
+    println!("next update: {}", format_utc(info.next_update));
+    println!();
+    let rows = info
+        .tables
+        .iter()
+        .map(|t| vec![t.name.clone(), t.rows.to_string()])
+        .collect::<Vec<Vec<String>>>();
+    print_table(&["table", "rows"], &rows);
     Ok(())
 }
+
+fn verify(config: &GeoliteConfig, format: Format) -> Result<(), GLErr> {
+    let problems = inspect::verify(config)?;
+    if format == Format::Json {
+        let out = serde_json::json!({ "ok": problems.is_empty(), "problems": problems });
+        println!("{}", serde_json::to_string_pretty(&out)?);
+    } else if problems.is_empty() {
+        println!("ok");
+    } else {
+        for p in problems.iter() {
+            println!("{}", p);
+        }
+    }
+    if !problems.is_empty() {
+        return Err(GLErr::VerificationFailed(problems));
+    }
+    Ok(())
+}
+
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(c, w)| format!("{:<width$}", c, width = w))
+            .collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(|c| c.as_str()).collect());
+    }
+}
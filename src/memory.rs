@@ -0,0 +1,713 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::config::Edition;
+use crate::errors::GLErr;
+use crate::network;
+use crate::reader::{AsnRecord, CityRecord, CountryRecord};
+
+// An address in one family, as an integer in address order.
+trait Key: Copy + Ord {
+    // The first address of the `len` bit network `self` is in.
+    fn first(self, len: u8) -> Self;
+
+    // Whether `self` falls in the network `start/len`.
+    fn within(self, start: Self, len: u8) -> bool {
+        self.first(len) == start
+    }
+}
+
+impl Key for u32 {
+    fn first(self, len: u8) -> u32 {
+        self & u32::MAX.checked_shl(32 - len as u32).unwrap_or(0)
+    }
+}
+
+impl Key for u128 {
+    fn first(self, len: u8) -> u128 {
+        self & u128::MAX.checked_shl(128 - len as u32).unwrap_or(0)
+    }
+}
+
+// The networks of one family sorted by first address, in parallel arrays.
+// GeoLite2 networks never overlap, so only the last network starting at or
+// before an address can contain it. Values are indexes into the owning
+// dataset's block list.
+struct Ranges<K> {
+    starts: Vec<K>,
+    lens: Vec<u8>,
+    values: Vec<u32>,
+}
+
+impl<K: Key> Ranges<K> {
+    fn new() -> Ranges<K> {
+        Ranges {
+            starts: Vec::new(),
+            lens: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, addr: K, len: u8, value: u32) {
+        self.starts.push(addr.first(len));
+        self.lens.push(len);
+        self.values.push(value);
+    }
+
+    // Sorts what was pushed, unless it came in order.
+    fn finish(&mut self) {
+        if !self.starts.windows(2).all(|w| w[0] <= w[1]) {
+            let mut order: Vec<u32> = (0..self.starts.len() as u32).collect();
+            order.sort_unstable_by_key(|i| self.starts[*i as usize]);
+            self.starts = order.iter().map(|i| self.starts[*i as usize]).collect();
+            self.lens = order.iter().map(|i| self.lens[*i as usize]).collect();
+            self.values = order.iter().map(|i| self.values[*i as usize]).collect();
+        }
+        self.starts.shrink_to_fit();
+        self.lens.shrink_to_fit();
+        self.values.shrink_to_fit();
+    }
+
+    // Value of the network containing `key` and its prefix length.
+    fn find(&self, key: K) -> Option<(u32, u8)> {
+        let i = self.starts.partition_point(|s| *s <= key).checked_sub(1)?;
+        let len = self.lens[i];
+        key.within(self.starts[i], len)
+            .then_some((self.values[i], len))
+    }
+}
+
+// Both families of one dataset's blocks.
+struct Networks {
+    v4: Ranges<u32>,
+    v6: Ranges<u128>,
+}
+
+impl Networks {
+    fn new() -> Networks {
+        Networks {
+            v4: Ranges::new(),
+            v6: Ranges::new(),
+        }
+    }
+
+    fn insert(&mut self, network: &str, value: u32) -> Result<(), GLErr> {
+        match network::parse_network(network)? {
+            (IpAddr::V4(a), len) => self.v4.push(u32::from(a), len, value),
+            (IpAddr::V6(a), len) => self.v6.push(u128::from(a), len, value),
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) {
+        self.v4.finish();
+        self.v6.finish();
+    }
+
+    fn find(&self, addr: IpAddr) -> Option<(u32, u8)> {
+        match addr {
+            IpAddr::V4(a) => self.v4.find(u32::from(a)),
+            IpAddr::V6(a) => self.v6.find(u128::from(a)),
+        }
+    }
+}
+
+fn format_network(addr: IpAddr, len: u8) -> String {
+    match addr {
+        IpAddr::V4(a) => format!("{}/{}", Ipv4Addr::from(u32::from(a).first(len)), len),
+        IpAddr::V6(a) => format!("{}/{}", Ipv6Addr::from(u128::from(a).first(len)), len),
+    }
+}
+
+struct CountryLocation {
+    geoname_id: i64,
+    continent_code: String,
+    continent_name: String,
+    country_iso_code: String,
+    country_name: String,
+    is_in_eu: bool,
+}
+
+struct CountryBlock {
+    location: u32,
+    is_anonymous_proxy: bool,
+    is_satellite_provider: bool,
+}
+
+struct CityLocation {
+    geoname_id: i64,
+    continent_code: String,
+    continent_name: String,
+    country_iso_code: String,
+    country_name: String,
+    subdivision_1_iso_code: String,
+    subdivision_1_name: String,
+    subdivision_2_iso_code: String,
+    subdivision_2_name: String,
+    city_name: String,
+    metro_code: String,
+    time_zone: String,
+    is_in_eu: bool,
+}
+
+struct CityBlock {
+    location: u32,
+    postal_code: String,
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    accuracy_radius_km: Option<i64>,
+}
+
+struct AsnBlock {
+    autonomous_system_number: i64,
+    org: u32,
+}
+
+/// Every block table of a `geolite2.db` held in memory. Location rows and
+/// AS organisations are stored once and shared by the blocks that use them.
+pub struct MemoryDb {
+    country_networks: Networks,
+    country_blocks: Vec<CountryBlock>,
+    country_locations: Vec<CountryLocation>,
+    city_networks: Networks,
+    city_blocks: Vec<CityBlock>,
+    city_locations: Vec<CityLocation>,
+    asn_networks: Networks,
+    asn_blocks: Vec<AsnBlock>,
+    asn_orgs: Vec<String>,
+}
+
+impl MemoryDb {
+    /// Loads the tables of `editions`.
+    pub fn load(conn: &rusqlite::Connection, editions: &[Edition]) -> Result<MemoryDb, GLErr> {
+        let mut db = MemoryDb {
+            country_networks: Networks::new(),
+            country_blocks: Vec::new(),
+            country_locations: Vec::new(),
+            city_networks: Networks::new(),
+            city_blocks: Vec::new(),
+            city_locations: Vec::new(),
+            asn_networks: Networks::new(),
+            asn_blocks: Vec::new(),
+            asn_orgs: Vec::new(),
+        };
+        for edition in editions {
+            match edition {
+                Edition::Country => db.load_countries(conn)?,
+                Edition::City => db.load_cities(conn)?,
+                Edition::Asn => db.load_asns(conn)?,
+            }
+        }
+        Ok(db)
+    }
+
+    fn load_countries(&mut self, conn: &rusqlite::Connection) -> Result<(), GLErr> {
+        let mut ids = HashMap::<i64, u32>::new();
+        let mut stmt = conn.prepare(
+            "SELECT geoname_id, continent_code, continent_name, country_iso_code,
+                country_name, is_in_eu
+            FROM countries",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(r) = rows.next()? {
+            let loc = CountryLocation {
+                geoname_id: r.get(0)?,
+                continent_code: r.get(1)?,
+                continent_name: r.get(2)?,
+                country_iso_code: r.get(3)?,
+                country_name: r.get(4)?,
+                is_in_eu: r.get(5)?,
+            };
+            ids.insert(loc.geoname_id, self.country_locations.len() as u32);
+            self.country_locations.push(loc);
+        }
+
+        for table in ["countries4", "countries6"] {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT network, COALESCE(geoname_id, registered_country_geoname_id),
+                    is_anonymous_proxy, is_satellite_provider
+                FROM {}",
+                table
+            ))?;
+            let mut rows = stmt.query([])?;
+            while let Some(r) = rows.next()? {
+                let id: Option<i64> = r.get(1)?;
+                let location = match id.and_then(|id| ids.get(&id)) {
+                    Some(l) => *l,
+                    None => continue,
+                };
+                self.country_networks
+                    .insert(&r.get::<_, String>(0)?, self.country_blocks.len() as u32)?;
+                self.country_blocks.push(CountryBlock {
+                    location,
+                    is_anonymous_proxy: r.get(2)?,
+                    is_satellite_provider: r.get(3)?,
+                });
+            }
+        }
+        self.country_networks.finish();
+        self.country_blocks.shrink_to_fit();
+        Ok(())
+    }
+
+    fn load_cities(&mut self, conn: &rusqlite::Connection) -> Result<(), GLErr> {
+        let mut ids = HashMap::<i64, u32>::new();
+        let mut stmt = conn.prepare(
+            "SELECT geoname_id, continent_code, continent_name, country_iso_code,
+                country_name, subdivision_1_iso_code, subdivision_1_name,
+                subdivision_2_iso_code, subdivision_2_name, city_name,
+                metro_code, time_zone, is_in_eu
+            FROM cities",
+        )?;
+        let mut rows = stmt.query([])?;
+        while let Some(r) = rows.next()? {
+            let loc = CityLocation {
+                geoname_id: r.get(0)?,
+                continent_code: r.get(1)?,
+                continent_name: r.get(2)?,
+                country_iso_code: r.get(3)?,
+                country_name: r.get(4)?,
+                subdivision_1_iso_code: r.get(5)?,
+                subdivision_1_name: r.get(6)?,
+                subdivision_2_iso_code: r.get(7)?,
+                subdivision_2_name: r.get(8)?,
+                city_name: r.get(9)?,
+                metro_code: r.get(10)?,
+                time_zone: r.get(11)?,
+                is_in_eu: r.get(12)?,
+            };
+            ids.insert(loc.geoname_id, self.city_locations.len() as u32);
+            self.city_locations.push(loc);
+        }
+
+        for table in ["cities4", "cities6"] {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT network, COALESCE(geoname_id, registered_country_geoname_id),
+                    postal_code, latitude, longitude, accuracy_radius_km
+                FROM {}",
+                table
+            ))?;
+            let mut rows = stmt.query([])?;
+            while let Some(r) = rows.next()? {
+                let id: Option<i64> = r.get(1)?;
+                let location = match id.and_then(|id| ids.get(&id)) {
+                    Some(l) => *l,
+                    None => continue,
+                };
+                self.city_networks
+                    .insert(&r.get::<_, String>(0)?, self.city_blocks.len() as u32)?;
+                self.city_blocks.push(CityBlock {
+                    location,
+                    postal_code: r.get(2)?,
+                    latitude: r.get(3)?,
+                    longitude: r.get(4)?,
+                    accuracy_radius_km: r.get(5)?,
+                });
+            }
+        }
+        self.city_networks.finish();
+        self.city_blocks.shrink_to_fit();
+        Ok(())
+    }
+
+    fn load_asns(&mut self, conn: &rusqlite::Connection) -> Result<(), GLErr> {
+        let mut orgs = HashMap::<String, u32>::new();
+        for table in ["asn4", "asn6"] {
+            let mut stmt = conn.prepare(&format!(
+                "SELECT network, autonomous_system_number, autonomous_system_org FROM {}",
+                table
+            ))?;
+            let mut rows = stmt.query([])?;
+            while let Some(r) = rows.next()? {
+                let name: String = r.get(2)?;
+                let org = match orgs.get(&name) {
+                    Some(o) => *o,
+                    None => {
+                        let o = self.asn_orgs.len() as u32;
+                        self.asn_orgs.push(name.clone());
+                        orgs.insert(name, o);
+                        o
+                    }
+                };
+                self.asn_networks
+                    .insert(&r.get::<_, String>(0)?, self.asn_blocks.len() as u32)?;
+                self.asn_blocks.push(AsnBlock {
+                    autonomous_system_number: r.get(1)?,
+                    org,
+                });
+            }
+        }
+        self.asn_networks.finish();
+        self.asn_blocks.shrink_to_fit();
+        Ok(())
+    }
+
+    pub fn country(&self, ip: IpAddr) -> Option<CountryRecord> {
+        let ip = ip.to_canonical();
+        let (idx, len) = self.country_networks.find(ip)?;
+        let block = &self.country_blocks[idx as usize];
+        let loc = &self.country_locations[block.location as usize];
+        Some(CountryRecord {
+            network: format_network(ip, len),
+            geoname_id: loc.geoname_id,
+            continent_code: loc.continent_code.clone(),
+            continent_name: loc.continent_name.clone(),
+            country_iso_code: loc.country_iso_code.clone(),
+            country_name: loc.country_name.clone(),
+            is_in_eu: loc.is_in_eu,
+            is_anonymous_proxy: block.is_anonymous_proxy,
+            is_satellite_provider: block.is_satellite_provider,
+        })
+    }
+
+    pub fn city(&self, ip: IpAddr) -> Option<CityRecord> {
+        let ip = ip.to_canonical();
+        let (idx, len) = self.city_networks.find(ip)?;
+        let block = &self.city_blocks[idx as usize];
+        let loc = &self.city_locations[block.location as usize];
+        Some(CityRecord {
+            network: format_network(ip, len),
+            geoname_id: loc.geoname_id,
+            continent_code: loc.continent_code.clone(),
+            continent_name: loc.continent_name.clone(),
+            country_iso_code: loc.country_iso_code.clone(),
+            country_name: loc.country_name.clone(),
+            subdivision_1_iso_code: loc.subdivision_1_iso_code.clone(),
+            subdivision_1_name: loc.subdivision_1_name.clone(),
+            subdivision_2_iso_code: loc.subdivision_2_iso_code.clone(),
+            subdivision_2_name: loc.subdivision_2_name.clone(),
+            city_name: loc.city_name.clone(),
+            metro_code: loc.metro_code.clone(),
+            time_zone: loc.time_zone.clone(),
+            is_in_eu: loc.is_in_eu,
+            postal_code: block.postal_code.clone(),
+            latitude: block.latitude,
+            longitude: block.longitude,
+            accuracy_radius_km: block.accuracy_radius_km,
+        })
+    }
+
+    pub fn asn(&self, ip: IpAddr) -> Option<AsnRecord> {
+        let ip = ip.to_canonical();
+        let (idx, len) = self.asn_networks.find(ip)?;
+        let block = &self.asn_blocks[idx as usize];
+        Some(AsnRecord {
+            network: format_network(ip, len),
+            autonomous_system_number: block.autonomous_system_number,
+            autonomous_system_org: self.asn_orgs[block.org as usize].clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::types::Value;
+
+    use super::*;
+    use crate::db;
+    use crate::reader::{Backend, GeoDb};
+
+    const DE: i64 = 2921044;
+    const US: i64 = 6252001;
+    const BERLIN: i64 = 2950159;
+    const MOUNTAIN_VIEW: i64 = 5375480;
+
+    // Adds a block with its `network_start`/`network_end` filled in.
+    fn block(conn: &rusqlite::Connection, table: &str, network: &str, cols: &[(&str, Value)]) {
+        let (start, end) = if network.contains(':') {
+            let (s, e) = network::v6_range(network).unwrap();
+            (
+                IpAddr::from(Ipv6Addr::from(s)),
+                IpAddr::from(Ipv6Addr::from(e)),
+            )
+        } else {
+            let (s, e) = network::v4_range(network).unwrap();
+            (
+                IpAddr::from(Ipv4Addr::from(s)),
+                IpAddr::from(Ipv4Addr::from(e)),
+            )
+        };
+        let mut values = vec![
+            Value::from(network.to_string()),
+            network::range_key(start),
+            network::range_key(end),
+        ];
+        values.extend(cols.iter().map(|(_, v)| v.clone()));
+        let names: Vec<&str> = cols.iter().map(|(c, _)| *c).collect();
+        conn.execute(
+            &format!(
+                "INSERT INTO {}(network, network_start, network_end{}) VALUES ({})",
+                table,
+                names.iter().map(|c| format!(", {}", c)).collect::<String>(),
+                vec!["?"; values.len()].join(", ")
+            ),
+            rusqlite::params_from_iter(values),
+        )
+        .unwrap();
+    }
+
+    fn country(conn: &rusqlite::Connection, table: &str, network: &str, id: Option<i64>) {
+        block(
+            conn,
+            table,
+            network,
+            &[
+                ("geoname_id", id.map_or(Value::Null, Value::from)),
+                ("registered_country_geoname_id", Value::from(US)),
+                ("is_anonymous_proxy", Value::from(false)),
+                (
+                    "is_satellite_provider",
+                    Value::from(network == "8.8.8.8/32"),
+                ),
+            ],
+        );
+    }
+
+    fn city(conn: &rusqlite::Connection, table: &str, network: &str, id: i64) {
+        block(
+            conn,
+            table,
+            network,
+            &[
+                ("geoname_id", Value::from(id)),
+                ("postal_code", Value::from(format!("{}", id % 100000))),
+                ("latitude", Value::from(52.5)),
+                ("longitude", Value::from(13.4)),
+                ("accuracy_radius_km", Value::from(20)),
+            ],
+        );
+    }
+
+    fn asn(conn: &rusqlite::Connection, table: &str, network: &str, number: i64, org: &str) {
+        block(
+            conn,
+            table,
+            network,
+            &[
+                ("autonomous_system_number", Value::from(number)),
+                ("autonomous_system_org", Value::from(org.to_string())),
+            ],
+        );
+    }
+
+    // The database file sits in the returned directory, which is removed
+    // when dropped.
+    fn fixture() -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("geolite2.db");
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        db::build_tables(&conn, &Edition::ALL).unwrap();
+        for (id, iso, eu) in [(DE, "DE", true), (US, "US", false)] {
+            conn.execute(
+                "INSERT INTO countries(geoname_id, continent_code, continent_name,
+                    country_iso_code, country_name, is_in_eu)
+                VALUES (?1, 'XX', 'Somewhere', ?2, ?2, ?3)",
+                (id, iso, eu),
+            )
+            .unwrap();
+        }
+        for (id, name) in [
+            (BERLIN, "Berlin"),
+            (MOUNTAIN_VIEW, "Mountain View"),
+            (US, ""),
+        ] {
+            conn.execute(
+                "INSERT INTO cities(geoname_id, continent_code, continent_name,
+                    country_iso_code, country_name, subdivision_1_iso_code,
+                    subdivision_1_name, subdivision_2_iso_code, subdivision_2_name,
+                    city_name, metro_code, time_zone, is_in_eu)
+                VALUES (?1, 'XX', 'Somewhere', 'XX', 'Somewhere', '', '', '', '',
+                    ?2, '', 'UTC', 0)",
+                (id, name),
+            )
+            .unwrap();
+        }
+        // Inserted out of order, with neighbouring networks, gaps and a block
+        // known only by its registered country
+        country(&conn, "countries4", "1.0.1.0/24", Some(US));
+        country(&conn, "countries4", "1.0.0.0/24", Some(DE));
+        country(&conn, "countries4", "1.0.4.0/22", None);
+        country(&conn, "countries4", "8.8.8.8/32", Some(US));
+        country(&conn, "countries6", "2a00::/64", Some(DE));
+        country(&conn, "countries6", "2001:200::/32", Some(DE));
+        country(&conn, "countries6", "2001:db8:1::/48", None);
+        city(&conn, "cities4", "1.0.0.128/25", MOUNTAIN_VIEW);
+        city(&conn, "cities4", "1.0.0.0/25", BERLIN);
+        city(&conn, "cities4", "8.8.8.0/24", MOUNTAIN_VIEW);
+        city(&conn, "cities6", "2001:200::/64", BERLIN);
+        city(&conn, "cities6", "2001:200:0:1::/64", MOUNTAIN_VIEW);
+        asn(&conn, "asn4", "8.8.8.0/24", 15169, "GOOGLE");
+        asn(&conn, "asn4", "1.0.0.0/24", 13335, "CLOUDFLARENET");
+        asn(&conn, "asn6", "2001:200::/32", 2500, "WIDE Project");
+        asn(&conn, "asn6", "2a00::/12", 2500, "WIDE Project");
+        db::build_indexes(&conn, &Edition::ALL).unwrap();
+        (dir, path)
+    }
+
+    #[test]
+    fn memory_lookups_match_sqlite() {
+        let (_dir, path) = fixture();
+        let sqlite = GeoDb::open_with(&path, Backend::Sqlite).unwrap();
+        let memory = GeoDb::open_with(&path, Backend::Memory).unwrap();
+        let ips = [
+            "0.0.0.0",
+            "1.0.0.0",
+            "1.0.0.127",
+            "1.0.0.128",
+            "1.0.0.255",
+            "1.0.1.0",
+            "1.0.2.0",
+            "1.0.4.0",
+            "1.0.7.255",
+            "1.0.8.0",
+            "8.8.8.7",
+            "8.8.8.8",
+            "8.8.8.9",
+            "255.255.255.255",
+            "::ffff:1.0.0.1",
+            "::ffff:1.0.0.200",
+            "::ffff:8.8.8.8",
+            "::ffff:9.9.9.9",
+            "::",
+            "::1",
+            "2001:1ff:ffff:ffff:ffff:ffff:ffff:ffff",
+            "2001:200::",
+            "2001:200::1",
+            "2001:200:0:1::5",
+            "2001:200:0:2::",
+            "2001:200:1::",
+            "2001:200:ffff:ffff:ffff:ffff:ffff:ffff",
+            "2001:201::",
+            "2001:db8:1:ffff::1",
+            "2a00::ffff",
+            "2a00:0:0:1::",
+            "2a0f:ffff::",
+            "ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+        ];
+        for ip in ips {
+            let ip: IpAddr = ip.parse().unwrap();
+            let expected = sqlite.lookup(ip).unwrap();
+            assert_eq!(memory.lookup(ip).unwrap(), expected, "{}", ip);
+            assert_eq!(memory.country(ip).unwrap(), expected.country, "{}", ip);
+            assert_eq!(memory.city(ip).unwrap(), expected.city, "{}", ip);
+            assert_eq!(memory.asn(ip).unwrap(), expected.asn, "{}", ip);
+        }
+    }
+
+    #[test]
+    fn first_and_last_addresses_of_every_block() {
+        let (_dir, path) = fixture();
+        // Each block's first and last address and the ones just outside it
+        let countries = [
+            ("0.255.255.255", None),
+            ("1.0.0.0", Some(("1.0.0.0/24", "DE"))),
+            ("1.0.0.255", Some(("1.0.0.0/24", "DE"))),
+            ("1.0.1.0", Some(("1.0.1.0/24", "US"))),
+            ("1.0.1.255", Some(("1.0.1.0/24", "US"))),
+            ("1.0.2.0", None),
+            ("1.0.3.255", None),
+            ("1.0.4.0", Some(("1.0.4.0/22", "US"))),
+            ("1.0.7.255", Some(("1.0.4.0/22", "US"))),
+            ("1.0.8.0", None),
+            ("8.8.8.7", None),
+            ("8.8.8.8", Some(("8.8.8.8/32", "US"))),
+            ("8.8.8.9", None),
+            ("::ffff:1.0.0.255", Some(("1.0.0.0/24", "DE"))),
+            ("2001:1ff:ffff:ffff:ffff:ffff:ffff:ffff", None),
+            ("2001:200::", Some(("2001:200::/32", "DE"))),
+            (
+                "2001:200:ffff:ffff:ffff:ffff:ffff:ffff",
+                Some(("2001:200::/32", "DE")),
+            ),
+            ("2001:201::", None),
+            ("2001:db8:0:ffff:ffff:ffff:ffff:ffff", None),
+            ("2001:db8:1::", Some(("2001:db8:1::/48", "US"))),
+            (
+                "2001:db8:1:ffff:ffff:ffff:ffff:ffff",
+                Some(("2001:db8:1::/48", "US")),
+            ),
+            ("2001:db8:2::", None),
+            ("2a00::", Some(("2a00::/64", "DE"))),
+            ("2a00::ffff:ffff:ffff:ffff", Some(("2a00::/64", "DE"))),
+            ("2a00:0:0:1::", None),
+        ];
+        let cities = [
+            ("1.0.0.0", Some(("1.0.0.0/25", "Berlin"))),
+            ("1.0.0.127", Some(("1.0.0.0/25", "Berlin"))),
+            ("1.0.0.128", Some(("1.0.0.128/25", "Mountain View"))),
+            ("1.0.0.255", Some(("1.0.0.128/25", "Mountain View"))),
+            ("1.0.1.0", None),
+            ("8.8.7.255", None),
+            ("8.8.8.0", Some(("8.8.8.0/24", "Mountain View"))),
+            ("8.8.8.255", Some(("8.8.8.0/24", "Mountain View"))),
+            ("8.8.9.0", None),
+            ("::ffff:1.0.0.128", Some(("1.0.0.128/25", "Mountain View"))),
+            ("2001:1ff:ffff:ffff:ffff:ffff:ffff:ffff", None),
+            ("2001:200::", Some(("2001:200::/64", "Berlin"))),
+            (
+                "2001:200::ffff:ffff:ffff:ffff",
+                Some(("2001:200::/64", "Berlin")),
+            ),
+            (
+                "2001:200:0:1::",
+                Some(("2001:200:0:1::/64", "Mountain View")),
+            ),
+            (
+                "2001:200:0:1:ffff:ffff:ffff:ffff",
+                Some(("2001:200:0:1::/64", "Mountain View")),
+            ),
+            ("2001:200:0:2::", None),
+        ];
+        let asns = [
+            ("0.255.255.255", None),
+            ("1.0.0.0", Some(("1.0.0.0/24", 13335))),
+            ("1.0.0.255", Some(("1.0.0.0/24", 13335))),
+            ("1.0.1.0", None),
+            ("8.8.7.255", None),
+            ("8.8.8.0", Some(("8.8.8.0/24", 15169))),
+            ("8.8.8.255", Some(("8.8.8.0/24", 15169))),
+            ("8.8.9.0", None),
+            ("::ffff:8.8.8.0", Some(("8.8.8.0/24", 15169))),
+            ("::", None),
+            ("2001:1ff:ffff:ffff:ffff:ffff:ffff:ffff", None),
+            ("2001:200::", Some(("2001:200::/32", 2500))),
+            (
+                "2001:200:ffff:ffff:ffff:ffff:ffff:ffff",
+                Some(("2001:200::/32", 2500)),
+            ),
+            ("2001:201::", None),
+            ("29ff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", None),
+            ("2a00::", Some(("2a00::/12", 2500))),
+            (
+                "2a0f:ffff:ffff:ffff:ffff:ffff:ffff:ffff",
+                Some(("2a00::/12", 2500)),
+            ),
+            ("2a10::", None),
+            ("ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff", None),
+        ];
+        for backend in [Backend::Sqlite, Backend::Memory] {
+            let db = GeoDb::open_with(&path, backend).unwrap();
+            for (ip, expected) in countries {
+                let found = db.country(ip.parse().unwrap()).unwrap();
+                let found = found
+                    .as_ref()
+                    .map(|r| (&r.network[..], &r.country_iso_code[..]));
+                assert_eq!(found, expected, "{} with {:?}", ip, backend);
+            }
+            for (ip, expected) in cities {
+                let found = db.city(ip.parse().unwrap()).unwrap();
+                let found = found.as_ref().map(|r| (&r.network[..], &r.city_name[..]));
+                assert_eq!(found, expected, "{} with {:?}", ip, backend);
+            }
+            for (ip, expected) in asns {
+                let found = db.asn(ip.parse().unwrap()).unwrap();
+                let found = found
+                    .as_ref()
+                    .map(|r| (&r.network[..], r.autonomous_system_number));
+                assert_eq!(found, expected, "{} with {:?}", ip, backend);
+            }
+        }
+    }
+}
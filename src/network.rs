@@ -0,0 +1,53 @@
+use std::net::IpAddr;
+
+use crate::errors::GLErr;
+
+/// Splits a CIDR string such as "1.0.0.0/24" into its address and prefix length.
+pub fn parse_network(network: &str) -> Result<(IpAddr, u8), GLErr> {
+    let invalid = || GLErr::InvalidNetwork(network.to_string());
+    let (addr, len) = network.split_once('/').ok_or_else(invalid)?;
+    let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+    let len: u8 = len.parse().map_err(|_| invalid())?;
+    let max = match addr {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    if len > max {
+        return Err(invalid());
+    }
+    Ok((addr, len))
+}
+
+/// First and last address of an IPv4 network.
+pub fn v4_range(network: &str) -> Result<(u32, u32), GLErr> {
+    match parse_network(network)? {
+        (IpAddr::V4(a), len) => {
+            let mask = u32::MAX.checked_shl(32 - len as u32).unwrap_or(0);
+            let start = u32::from(a) & mask;
+            Ok((start, start | !mask))
+        }
+        _ => Err(GLErr::InvalidNetwork(network.to_string())),
+    }
+}
+
+/// First and last address of an IPv6 network.
+pub fn v6_range(network: &str) -> Result<(u128, u128), GLErr> {
+    match parse_network(network)? {
+        (IpAddr::V6(a), len) => {
+            let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+            let start = u128::from(a) & mask;
+            Ok((start, start | !mask))
+        }
+        _ => Err(GLErr::InvalidNetwork(network.to_string())),
+    }
+}
+
+/// How an address is stored in the `network_start`/`network_end` columns:
+/// an integer for IPv4 and a 16 byte big-endian blob for IPv6, so both
+/// compare in address order.
+pub fn range_key(ip: IpAddr) -> rusqlite::types::Value {
+    match ip.to_canonical() {
+        IpAddr::V4(a) => rusqlite::types::Value::Integer(u32::from(a) as i64),
+        IpAddr::V6(a) => rusqlite::types::Value::Blob(u128::from(a).to_be_bytes().to_vec()),
+    }
+}
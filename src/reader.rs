@@ -0,0 +1,326 @@
+use std::{net::IpAddr, sync::Mutex};
+
+use crate::config::Edition;
+use crate::db;
+use crate::errors::GLErr;
+use crate::memory::MemoryDb;
+use crate::network;
+use rusqlite::OptionalExtension;
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CountryRecord {
+    pub network: String,
+    pub geoname_id: i64,
+    pub continent_code: String,
+    pub continent_name: String,
+    pub country_iso_code: String,
+    pub country_name: String,
+    pub is_in_eu: bool,
+    pub is_anonymous_proxy: bool,
+    pub is_satellite_provider: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CityRecord {
+    pub network: String,
+    pub geoname_id: i64,
+    pub continent_code: String,
+    pub continent_name: String,
+    pub country_iso_code: String,
+    pub country_name: String,
+    pub subdivision_1_iso_code: String,
+    pub subdivision_1_name: String,
+    pub subdivision_2_iso_code: String,
+    pub subdivision_2_name: String,
+    pub city_name: String,
+    pub metro_code: String,
+    pub time_zone: String,
+    pub is_in_eu: bool,
+    pub postal_code: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub accuracy_radius_km: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AsnRecord {
+    pub network: String,
+    pub autonomous_system_number: i64,
+    pub autonomous_system_org: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct GeoRecord {
+    pub country: Option<CountryRecord>,
+    pub city: Option<CityRecord>,
+    pub asn: Option<AsnRecord>,
+}
+
+/// Where a `GeoDb` answers lookups from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Indexed queries against the database file.
+    #[default]
+    Sqlite,
+    /// Block tables loaded once into memory, each address family as arrays
+    /// of networks sorted by first address and searched with a binary
+    /// search, which takes far less memory than a trie over address bits.
+    /// Much faster lookups at the cost of holding every dataset in RAM.
+    Memory,
+}
+
+enum Inner {
+    // Connections are not Sync; the mutex lets a GeoDb be shared by threads.
+    Sqlite(Mutex<rusqlite::Connection>),
+    Memory(Box<MemoryDb>),
+}
+
+/// Read-only handle on a `geolite2.db` built by `db::update_db`.
+pub struct GeoDb {
+    inner: Inner,
+    editions: Vec<Edition>,
+}
+
+impl GeoDb {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<GeoDb, GLErr> {
+        GeoDb::open_with(path, Backend::Sqlite)
+    }
+
+    pub fn open_with<P: AsRef<std::path::Path>>(path: P, backend: Backend) -> Result<GeoDb, GLErr> {
+        let conn = rusqlite::Connection::open_with_flags(
+            path,
+            rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY | rusqlite::OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        let editions = db::built_editions(&conn)?;
+        let inner = match backend {
+            Backend::Sqlite => Inner::Sqlite(Mutex::new(conn)),
+            Backend::Memory => Inner::Memory(Box::new(MemoryDb::load(&conn, &editions)?)),
+        };
+        Ok(GeoDb { inner, editions })
+    }
+
+    /// Editions the database was built with. Lookups needing any other one
+    /// fail with `GLErr::EditionNotBuilt`.
+    pub fn editions(&self) -> &[Edition] {
+        &self.editions
+    }
+
+    fn require(&self, edition: Edition) -> Result<(), GLErr> {
+        if !self.editions.contains(&edition) {
+            return Err(GLErr::EditionNotBuilt(edition));
+        }
+        Ok(())
+    }
+
+    /// Country of the network containing `ip`. Falls back to the registered
+    /// country when the block has no `geoname_id`.
+    pub fn country(&self, ip: IpAddr) -> Result<Option<CountryRecord>, GLErr> {
+        self.require(Edition::Country)?;
+        let ip = ip.to_canonical();
+        match &self.inner {
+            Inner::Sqlite(conn) => query(conn, &country_select(ip), ip, |r| country_from_row(r, 0)),
+            Inner::Memory(m) => Ok(m.country(ip)),
+        }
+    }
+
+    /// City block containing `ip` joined to its location row.
+    pub fn city(&self, ip: IpAddr) -> Result<Option<CityRecord>, GLErr> {
+        self.require(Edition::City)?;
+        let ip = ip.to_canonical();
+        match &self.inner {
+            Inner::Sqlite(conn) => query(conn, &city_select(ip), ip, |r| city_from_row(r, 0)),
+            Inner::Memory(m) => Ok(m.city(ip)),
+        }
+    }
+
+    /// Autonomous system announcing the network containing `ip`.
+    pub fn asn(&self, ip: IpAddr) -> Result<Option<AsnRecord>, GLErr> {
+        self.require(Edition::Asn)?;
+        let ip = ip.to_canonical();
+        match &self.inner {
+            Inner::Sqlite(conn) => query(conn, &asn_select(ip), ip, |r| asn_from_row(r, 0)),
+            Inner::Memory(m) => Ok(m.asn(ip)),
+        }
+    }
+
+    /// Country, city and ASN for `ip` in a single query. A section is `None`
+    /// when its dataset has no network containing `ip` or was not built.
+    pub fn lookup(&self, ip: IpAddr) -> Result<GeoRecord, GLErr> {
+        let ip = ip.to_canonical();
+        let conn = match &self.inner {
+            Inner::Sqlite(conn) => conn,
+            Inner::Memory(m) => {
+                return Ok(GeoRecord {
+                    country: m.country(ip),
+                    city: m.city(ip),
+                    asn: m.asn(ip),
+                })
+            }
+        };
+        // An edition that was not built stands in as an empty section
+        let section = |edition, select: fn(IpAddr) -> String, cols| {
+            if self.editions.contains(&edition) {
+                select(ip)
+            } else {
+                format!("SELECT {} WHERE 0", vec!["NULL"; cols].join(", "))
+            }
+        };
+        let q = format!(
+            "WITH co AS ({}), ci AS ({}), a AS ({})
+            SELECT co.*, ci.*, a.* FROM (SELECT 1)
+            LEFT JOIN co LEFT JOIN ci LEFT JOIN a",
+            section(Edition::Country, country_select, COUNTRY_COLS),
+            section(Edition::City, city_select, CITY_COLS),
+            section(Edition::Asn, asn_select, ASN_COLS),
+        );
+        let rec = query(conn, &q, ip, |r| {
+            Ok(GeoRecord {
+                country: optional_section(r, 0, country_from_row)?,
+                city: optional_section(r, COUNTRY_COLS, city_from_row)?,
+                asn: optional_section(r, COUNTRY_COLS + CITY_COLS, asn_from_row)?,
+            })
+        })?;
+        Ok(rec.unwrap_or_default())
+    }
+}
+
+fn query<T, F>(
+    conn: &Mutex<rusqlite::Connection>,
+    q: &str,
+    ip: IpAddr,
+    f: F,
+) -> Result<Option<T>, GLErr>
+where
+    F: FnOnce(&rusqlite::Row<'_>) -> rusqlite::Result<T>,
+{
+    let conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+    let mut stmt = conn.prepare_cached(q)?;
+    Ok(stmt.query_row([network::range_key(ip)], f).optional()?)
+}
+
+const COUNTRY_COLS: usize = 9;
+const CITY_COLS: usize = 18;
+const ASN_COLS: usize = 3;
+
+fn country_select(ip: IpAddr) -> String {
+    let table = match ip {
+        IpAddr::V4(_) => "countries4",
+        IpAddr::V6(_) => "countries6",
+    };
+    format!(
+        "SELECT b.network, c.geoname_id, c.continent_code, c.continent_name,
+            c.country_iso_code, c.country_name, c.is_in_eu,
+            b.is_anonymous_proxy, b.is_satellite_provider
+        FROM {} b
+        JOIN countries c
+            ON c.geoname_id = COALESCE(b.geoname_id, b.registered_country_geoname_id)
+        WHERE {}",
+        table,
+        containing("b", table)
+    )
+}
+
+fn country_from_row(r: &rusqlite::Row<'_>, o: usize) -> rusqlite::Result<CountryRecord> {
+    Ok(CountryRecord {
+        network: r.get(o)?,
+        geoname_id: r.get(o + 1)?,
+        continent_code: r.get(o + 2)?,
+        continent_name: r.get(o + 3)?,
+        country_iso_code: r.get(o + 4)?,
+        country_name: r.get(o + 5)?,
+        is_in_eu: r.get(o + 6)?,
+        is_anonymous_proxy: r.get(o + 7)?,
+        is_satellite_provider: r.get(o + 8)?,
+    })
+}
+
+fn city_select(ip: IpAddr) -> String {
+    let table = match ip {
+        IpAddr::V4(_) => "cities4",
+        IpAddr::V6(_) => "cities6",
+    };
+    format!(
+        "SELECT b.network, c.geoname_id, c.continent_code, c.continent_name,
+            c.country_iso_code, c.country_name, c.subdivision_1_iso_code,
+            c.subdivision_1_name, c.subdivision_2_iso_code, c.subdivision_2_name,
+            c.city_name, c.metro_code, c.time_zone, c.is_in_eu,
+            b.postal_code, b.latitude, b.longitude, b.accuracy_radius_km
+        FROM {} b
+        JOIN cities c
+            ON c.geoname_id = COALESCE(b.geoname_id, b.registered_country_geoname_id)
+        WHERE {}",
+        table,
+        containing("b", table)
+    )
+}
+
+fn city_from_row(r: &rusqlite::Row<'_>, o: usize) -> rusqlite::Result<CityRecord> {
+    Ok(CityRecord {
+        network: r.get(o)?,
+        geoname_id: r.get(o + 1)?,
+        continent_code: r.get(o + 2)?,
+        continent_name: r.get(o + 3)?,
+        country_iso_code: r.get(o + 4)?,
+        country_name: r.get(o + 5)?,
+        subdivision_1_iso_code: r.get(o + 6)?,
+        subdivision_1_name: r.get(o + 7)?,
+        subdivision_2_iso_code: r.get(o + 8)?,
+        subdivision_2_name: r.get(o + 9)?,
+        city_name: r.get(o + 10)?,
+        metro_code: r.get(o + 11)?,
+        time_zone: r.get(o + 12)?,
+        is_in_eu: r.get(o + 13)?,
+        postal_code: r.get(o + 14)?,
+        latitude: r.get(o + 15)?,
+        longitude: r.get(o + 16)?,
+        accuracy_radius_km: r.get(o + 17)?,
+    })
+}
+
+fn asn_select(ip: IpAddr) -> String {
+    let table = match ip {
+        IpAddr::V4(_) => "asn4",
+        IpAddr::V6(_) => "asn6",
+    };
+    format!(
+        "SELECT b.network, b.autonomous_system_number, b.autonomous_system_org
+        FROM {} b
+        WHERE {}",
+        table,
+        containing("b", table)
+    )
+}
+
+fn asn_from_row(r: &rusqlite::Row<'_>, o: usize) -> rusqlite::Result<AsnRecord> {
+    Ok(AsnRecord {
+        network: r.get(o)?,
+        autonomous_system_number: r.get(o + 1)?,
+        autonomous_system_org: r.get(o + 2)?,
+    })
+}
+
+// A LEFT JOINed section is all NULL when its dataset had no match.
+fn optional_section<T>(
+    r: &rusqlite::Row<'_>,
+    o: usize,
+    f: fn(&rusqlite::Row<'_>, usize) -> rusqlite::Result<T>,
+) -> rusqlite::Result<Option<T>> {
+    if r.get::<_, Option<String>>(o)?.is_none() {
+        return Ok(None);
+    }
+    f(r, o).map(Some)
+}
+
+// GeoLite2 networks never overlap, so the only candidate is the block with
+// the greatest start at or below ?1; it contains ?1 if its end reaches it.
+// Both halves are index seeks on `network_start`.
+fn containing(alias: &str, table: &str) -> String {
+    format!(
+        "{a}.network_start = (
+            SELECT MAX(network_start) FROM {t} WHERE network_start <= ?1
+        ) AND {a}.network_end >= ?1",
+        a = alias,
+        t = table
+    )
+}
@@ -0,0 +1,382 @@
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Deserialize;
+
+use crate::errors::GLErr;
+
+// The longest interval accepted, also used for cron expressions that never
+// match.
+const MAX_INTERVAL: Duration = Duration::from_secs(3600 * 24 * 366 * 100);
+
+/// When a database built at some time is due to be rebuilt.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Schedule {
+    /// A fixed time after the last build.
+    Interval(Duration),
+    /// The first time after the last build matching a five field cron
+    /// expression, evaluated in UTC.
+    Cron(CronExpr),
+    /// The day after each GeoLite2 release. MaxMind publishes on Tuesdays and
+    /// Fridays, so this is 00:00 UTC on Wednesdays and Saturdays.
+    ReleaseDays,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule::Interval(Duration::from_secs(3600 * 24 * 7))
+    }
+}
+
+impl Schedule {
+    pub fn next_after(&self, last: SystemTime) -> SystemTime {
+        match self {
+            // Only overflows for a `last` far in the future, in which case
+            // the database is due right away.
+            Schedule::Interval(d) => last.checked_add(*d).unwrap_or(last),
+            Schedule::Cron(c) => c.next_after(last),
+            Schedule::ReleaseDays => CronExpr::release_days().next_after(last),
+        }
+    }
+}
+
+/// Accepts `release-days`, an interval such as `7d`, `12h`, `30m` or `90s`
+/// of up to 100 years, or a cron expression.
+impl FromStr for Schedule {
+    type Err = GLErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "release-days" {
+            return Ok(Schedule::ReleaseDays);
+        }
+        if s.split_whitespace().count() == 5 {
+            return Ok(Schedule::Cron(s.parse()?));
+        }
+        let invalid = || GLErr::InvalidSchedule(s.to_string());
+        let unit = match s.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 3600,
+            Some('d') => 3600 * 24,
+            _ => return Err(invalid()),
+        };
+        let n: u64 = s[..s.len() - 1].parse().map_err(|_| invalid())?;
+        let secs = n.checked_mul(unit).ok_or_else(invalid)?;
+        if secs > MAX_INTERVAL.as_secs() {
+            return Err(invalid());
+        }
+        Ok(Schedule::Interval(Duration::from_secs(secs)))
+    }
+}
+
+impl TryFrom<String> for Schedule {
+    type Error = GLErr;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// `minute hour day-of-month month day-of-week` with `*`, lists, ranges and
+/// steps. Days of the week run 0-7 with both 0 and 7 meaning Sunday.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl CronExpr {
+    fn release_days() -> CronExpr {
+        "0 0 * * 3,6".parse().expect("valid cron expression")
+    }
+
+    /// The first matching minute strictly after `last`.
+    pub fn next_after(&self, last: SystemTime) -> SystemTime {
+        let secs = last
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let start = secs / 60 + 1;
+        let first = start / (60 * 24);
+        let mut from = start % (60 * 24);
+        // Every expression matches at least once in any eight year window.
+        for day in first..first + 366 * 8 {
+            if self.day_matches(day) {
+                for m in from..60 * 24 {
+                    if self.hours & (1 << (m / 60)) != 0 && self.minutes & (1 << (m % 60)) != 0 {
+                        return UNIX_EPOCH + Duration::from_secs((day * 60 * 24 + m) * 60);
+                    }
+                }
+            }
+            from = 0;
+        }
+        // Never, e.g. "0 0 30 2 *"
+        last.checked_add(MAX_INTERVAL).unwrap_or(last)
+    }
+
+    fn day_matches(&self, day: u64) -> bool {
+        let (_, month, dom) = civil_from_days(day);
+        if self.months & (1 << month) == 0 {
+            return false;
+        }
+        // 1970-01-01 was a Thursday.
+        let weekday = (day + 4) % 7;
+        let dom_ok = self.days & (1 << dom) != 0;
+        let dow_ok = self.weekdays & (1 << weekday) != 0;
+        // Like cron, restricting both fields matches either of them.
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => dow_ok,
+            (false, true) => dom_ok,
+            (false, false) => dom_ok || dow_ok,
+        }
+    }
+}
+
+impl FromStr for CronExpr {
+    type Err = GLErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(GLErr::InvalidSchedule(s.to_string()));
+        }
+        let weekdays = cron_field(s, fields[4], 0, 7)?;
+        Ok(CronExpr {
+            minutes: cron_field(s, fields[0], 0, 59)?,
+            hours: cron_field(s, fields[1], 0, 23)? as u32,
+            days: cron_field(s, fields[2], 1, 31)? as u32,
+            months: cron_field(s, fields[3], 1, 12)? as u16,
+            weekdays: ((weekdays | (weekdays >> 7)) & 0x7f) as u8,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        })
+    }
+}
+
+// Bitset of the values selected by one field.
+fn cron_field(expr: &str, field: &str, min: u64, max: u64) -> Result<u64, GLErr> {
+    let invalid = || GLErr::InvalidSchedule(expr.to_string());
+    let mut set = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((r, st)) => (r, st.parse::<u64>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (lo, hi) = if range == "*" {
+            (min, max)
+        } else if let Some((lo, hi)) = range.split_once('-') {
+            (
+                lo.parse().map_err(|_| invalid())?,
+                hi.parse().map_err(|_| invalid())?,
+            )
+        } else {
+            let v: u64 = range.parse().map_err(|_| invalid())?;
+            // "5/15" means from 5 to the end in steps of 15
+            (v, if step > 1 { max } else { v })
+        };
+        if step == 0 || lo < min || hi > max || lo > hi {
+            return Err(invalid());
+        }
+        for v in (lo..=hi).step_by(step as usize) {
+            set |= 1 << v;
+        }
+    }
+    Ok(set)
+}
+
+/// `secs` since the epoch as `YYYY-MM-DD HH:MM:SS UTC`.
+pub fn format_utc(secs: u64) -> String {
+    let (y, m, d) = civil_from_days(secs / 86400);
+    let t = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        y,
+        m,
+        d,
+        t / 3600,
+        t / 60 % 60,
+        t % 60
+    )
+}
+
+// (year, month, day) of a day count since the epoch. From Howard Hinnant's
+// date algorithms.
+fn civil_from_days(days: u64) -> (i64, u64, u64) {
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u64;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Seconds since the epoch of a UTC date and time.
+    fn at(y: i64, m: i64, d: i64, hh: u64, mm: u64) -> SystemTime {
+        let y = if m <= 2 { y - 1 } else { y };
+        let era = y.div_euclid(400);
+        let yoe = y - era * 400;
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = (era * 146097 + doe - 719468) as u64;
+        UNIX_EPOCH + Duration::from_secs(days * 86400 + hh * 3600 + mm * 60)
+    }
+
+    fn utc(t: SystemTime) -> String {
+        format_utc(t.duration_since(UNIX_EPOCH).unwrap().as_secs())
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(11016), (2000, 2, 29));
+        assert_eq!(civil_from_days(19782), (2024, 2, 29));
+        assert_eq!(civil_from_days(19783), (2024, 3, 1));
+        assert_eq!(format_utc(19398 * 86400 + 3723), "2023-02-10 01:02:03 UTC");
+    }
+
+    #[test]
+    fn cron_fields() {
+        let bits = |vs: &[u64]| vs.iter().fold(0u64, |s, v| s | 1 << v);
+        assert_eq!(
+            cron_field("", "*", 1, 12).unwrap(),
+            bits(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12])
+        );
+        assert_eq!(
+            cron_field("", "*/15", 0, 59).unwrap(),
+            bits(&[0, 15, 30, 45])
+        );
+        assert_eq!(cron_field("", "5/20", 0, 59).unwrap(), bits(&[5, 25, 45]));
+        assert_eq!(cron_field("", "1-5", 0, 7).unwrap(), bits(&[1, 2, 3, 4, 5]));
+        assert_eq!(
+            cron_field("", "10-20/5", 0, 59).unwrap(),
+            bits(&[10, 15, 20])
+        );
+        assert_eq!(
+            cron_field("", "1,3,9-10", 1, 31).unwrap(),
+            bits(&[1, 3, 9, 10])
+        );
+
+        // 7 is Sunday as well as 0
+        let sunday: CronExpr = "0 0 * * 7".parse().unwrap();
+        assert_eq!(sunday.weekdays, 1);
+        assert_eq!(sunday, "0 0 * * 0".parse().unwrap());
+    }
+
+    #[test]
+    fn rejected_schedules() {
+        for s in [
+            "",
+            "7",
+            "7w",
+            "d",
+            "-1d",
+            "1.5h",
+            "99999999999999d",
+            "18446744073709551615m",
+            "36601d",
+            "* * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "5-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+            "1,,2 * * * *",
+        ] {
+            assert!(
+                matches!(s.parse::<Schedule>(), Err(GLErr::InvalidSchedule(_))),
+                "{:?} was accepted",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn intervals() {
+        let s: Schedule = "90s".parse().unwrap();
+        assert_eq!(
+            s.next_after(UNIX_EPOCH),
+            UNIX_EPOCH + Duration::from_secs(90)
+        );
+        assert_eq!(
+            "7d".parse::<Schedule>().unwrap(),
+            Schedule::Interval(Duration::from_secs(7 * 86400))
+        );
+
+        // The longest interval can be applied repeatedly without overflowing
+        let s: Schedule = "36600d".parse().unwrap();
+        let mut t = SystemTime::now();
+        for _ in 0..3 {
+            t = s.next_after(t);
+        }
+        assert!(t > SystemTime::now());
+    }
+
+    #[test]
+    fn cron_next_after() {
+        let s: Schedule = "30 2 * * *".parse().unwrap();
+        let t = s.next_after(at(2023, 2, 10, 0, 0));
+        assert_eq!(utc(t), "2023-02-10 02:30:00 UTC");
+        // Strictly after
+        assert_eq!(utc(s.next_after(t)), "2023-02-11 02:30:00 UTC");
+
+        let s: Schedule = "0 0 29 2 *".parse().unwrap();
+        assert_eq!(
+            utc(s.next_after(at(2023, 2, 10, 0, 0))),
+            "2024-02-29 00:00:00 UTC"
+        );
+
+        let s: Schedule = "0 0 30 2 *".parse().unwrap();
+        let last = at(2023, 2, 10, 0, 0);
+        assert_eq!(s.next_after(last), last + MAX_INTERVAL);
+    }
+
+    #[test]
+    fn day_of_month_or_day_of_week() {
+        // The 13th or any Friday; 2023-02-10 and 2023-02-17 are Fridays
+        let s: Schedule = "0 0 13 * 5".parse().unwrap();
+        let t = s.next_after(at(2023, 2, 10, 0, 0));
+        assert_eq!(utc(t), "2023-02-13 00:00:00 UTC");
+        assert_eq!(utc(s.next_after(t)), "2023-02-17 00:00:00 UTC");
+
+        // Only the 13th
+        let s: Schedule = "0 0 13 * *".parse().unwrap();
+        let t = s.next_after(at(2023, 2, 10, 0, 0));
+        assert_eq!(utc(t), "2023-02-13 00:00:00 UTC");
+        assert_eq!(utc(s.next_after(t)), "2023-03-13 00:00:00 UTC");
+
+        // Only Fridays
+        let s: Schedule = "0 0 * * 5".parse().unwrap();
+        let t = s.next_after(at(2023, 2, 10, 0, 0));
+        assert_eq!(utc(t), "2023-02-17 00:00:00 UTC");
+    }
+
+    #[test]
+    fn release_days() {
+        // From a Tuesday to the Wednesday and Saturday after it
+        let s: Schedule = "release-days".parse().unwrap();
+        let t = s.next_after(at(2023, 2, 14, 12, 0));
+        assert_eq!(utc(t), "2023-02-15 00:00:00 UTC");
+        assert_eq!(utc(s.next_after(t)), "2023-02-18 00:00:00 UTC");
+    }
+}
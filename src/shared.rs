@@ -0,0 +1,181 @@
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+
+use crate::errors::GLErr;
+use crate::reader::{AsnRecord, Backend, CityRecord, CountryRecord, GeoDb, GeoRecord};
+
+/// A `GeoDb` that follows `update_db`. A background task polls the `version`
+/// file in the database directory and, when it changes, opens the new
+/// `geolite2.db` and swaps it in. Lookups already running keep the handle they
+/// started with.
+pub struct SharedGeoDb {
+    current: Arc<ArcSwap<GeoDb>>,
+    watcher: tokio::task::JoinHandle<()>,
+}
+
+impl SharedGeoDb {
+    /// Must be called from within a tokio runtime.
+    pub async fn open<P: AsRef<Path>>(
+        db_dir: P,
+        backend: Backend,
+        poll: Duration,
+    ) -> Result<SharedGeoDb, GLErr> {
+        let db_dir = db_dir.as_ref().to_path_buf();
+        // Read the version before opening so the data is never older than it.
+        let seen = read_version(&db_dir);
+        let path = db_dir.join("geolite2.db");
+        let db = tokio::task::spawn_blocking(move || GeoDb::open_with(path, backend)).await??;
+        let current = Arc::new(ArcSwap::from_pointee(db));
+        let watcher = tokio::spawn(watch(db_dir, backend, poll, seen, Arc::clone(&current)));
+        Ok(SharedGeoDb { current, watcher })
+    }
+
+    /// The database currently being served.
+    pub fn current(&self) -> Arc<GeoDb> {
+        self.current.load_full()
+    }
+
+    pub fn country(&self, ip: IpAddr) -> Result<Option<CountryRecord>, GLErr> {
+        self.current.load().country(ip)
+    }
+
+    pub fn city(&self, ip: IpAddr) -> Result<Option<CityRecord>, GLErr> {
+        self.current.load().city(ip)
+    }
+
+    pub fn asn(&self, ip: IpAddr) -> Result<Option<AsnRecord>, GLErr> {
+        self.current.load().asn(ip)
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Result<GeoRecord, GLErr> {
+        self.current.load().lookup(ip)
+    }
+}
+
+impl Drop for SharedGeoDb {
+    fn drop(&mut self) {
+        self.watcher.abort();
+    }
+}
+
+async fn watch(
+    db_dir: PathBuf,
+    backend: Backend,
+    poll: Duration,
+    mut seen: Option<String>,
+    current: Arc<ArcSwap<GeoDb>>,
+) {
+    let mut ticker = tokio::time::interval(poll);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let version = read_version(&db_dir);
+        if version.is_none() || version == seen {
+            continue;
+        }
+        let path = db_dir.join("geolite2.db");
+        match tokio::task::spawn_blocking(move || GeoDb::open_with(path, backend)).await {
+            Ok(Ok(db)) => {
+                current.store(Arc::new(db));
+                seen = version;
+            }
+            // Left unseen so the next tick retries.
+            Ok(Err(e)) => eprintln!("{:?}", e),
+            Err(e) => eprintln!("{:?}", e),
+        }
+    }
+}
+
+fn read_version(db_dir: &Path) -> Option<String> {
+    std::fs::read_to_string(db_dir.join("version"))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Edition;
+    use crate::db;
+
+    const IP: &str = "1.0.0.1";
+
+    // Writes a database whose only block, 1.0.0.0/24, belongs to AS `asn`,
+    // and renames it into place like `update_db`.
+    fn build(db_dir: &Path, asn: i64, version: &str) {
+        let tmp = db_dir.join("geolite2.db.new");
+        let conn = rusqlite::Connection::open(&tmp).unwrap();
+        db::build_tables(&conn, &[Edition::Asn]).unwrap();
+        conn.execute(
+            "INSERT INTO asn4 VALUES ('1.0.0.0/24', ?1, 'AS', 16777216, 16777471)",
+            [asn],
+        )
+        .unwrap();
+        drop(conn);
+        std::fs::rename(tmp, db_dir.join("geolite2.db")).unwrap();
+        std::fs::write(db_dir.join("version"), version).unwrap();
+    }
+
+    fn asn(db: &SharedGeoDb) -> i64 {
+        let asn = db.asn(IP.parse().unwrap()).unwrap();
+        asn.unwrap().autonomous_system_number
+    }
+
+    // Waits up to a second for `db` to serve `want`.
+    async fn serves(db: &SharedGeoDb, want: i64) -> bool {
+        for _ in 0..100 {
+            if asn(db) == want {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn new_builds_are_swapped_in() {
+        let dir = tempfile::tempdir().unwrap();
+        build(dir.path(), 1, "1");
+        let db = SharedGeoDb::open(dir.path(), Backend::Sqlite, Duration::from_millis(10))
+            .await
+            .unwrap();
+        assert_eq!(asn(&db), 1);
+        let before = db.current();
+
+        build(dir.path(), 2, "2");
+        assert!(serves(&db, 2).await);
+        // A handle taken earlier keeps answering from its own build
+        let old = before.asn(IP.parse().unwrap()).unwrap().unwrap();
+        assert_eq!(old.autonomous_system_number, 1);
+    }
+
+    #[tokio::test]
+    async fn builds_that_fail_to_open_keep_the_old_one() {
+        let dir = tempfile::tempdir().unwrap();
+        build(dir.path(), 1, "1");
+        let db = SharedGeoDb::open(dir.path(), Backend::Memory, Duration::from_millis(10))
+            .await
+            .unwrap();
+
+        std::fs::write(dir.path().join("geolite2.db"), "not a database").unwrap();
+        std::fs::write(dir.path().join("version"), "2").unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(asn(&db), 1);
+
+        // The same version is tried again once the database can be opened
+        build(dir.path(), 3, "2");
+        assert!(serves(&db, 3).await);
+
+        // Dropping it stops the watcher, which holds the other reference
+        let current = Arc::downgrade(&db.current);
+        drop(db);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(current.upgrade().is_none());
+    }
+}
@@ -0,0 +1,79 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Edition;
+use crate::errors::GLErr;
+
+/// Validators MaxMind sent with an archive, used to tell whether a newer
+/// release is out without downloading it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteMeta {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl RemoteMeta {
+    pub fn from_headers(headers: &reqwest::header::HeaderMap) -> RemoteMeta {
+        let get = |name: reqwest::header::HeaderName| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+        };
+        RemoteMeta {
+            etag: get(reqwest::header::ETAG),
+            last_modified: get(reqwest::header::LAST_MODIFIED),
+        }
+    }
+
+    /// Whether `self` and `other` identify the same release. Without any
+    /// validator there is no way to tell, so that never matches.
+    pub fn same_release(&self, other: &RemoteMeta) -> bool {
+        match (&self.etag, &other.etag) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.last_modified.is_some() && self.last_modified == other.last_modified,
+        }
+    }
+}
+
+/// `sources.json`, kept next to `version`: what each edition in the live
+/// database was built from and when MaxMind was last asked about updates.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Sources {
+    /// Seconds since the epoch of the last update check.
+    pub checked: u64,
+    pub editions: HashMap<String, RemoteMeta>,
+    /// Releases whose build was refused for differing too much from the live
+    /// database. They are not fetched again unless forced.
+    #[serde(default)]
+    pub rejected: HashMap<String, RemoteMeta>,
+}
+
+impl Sources {
+    pub fn read(db_dir: &Path) -> Sources {
+        std::fs::read_to_string(db_dir.join("sources.json"))
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn write(&self, db_dir: &Path) -> Result<(), GLErr> {
+        let tmp = db_dir.join("sources.json.new");
+        std::fs::write(&tmp, serde_json::to_string_pretty(self)?)?;
+        std::fs::rename(tmp, db_dir.join("sources.json"))?;
+        Ok(())
+    }
+
+    pub fn get(&self, edition: Edition) -> Option<&RemoteMeta> {
+        self.editions.get(edition.id())
+    }
+
+    pub fn rejected(&self, edition: Edition) -> Option<&RemoteMeta> {
+        self.rejected.get(edition.id())
+    }
+
+    pub fn set(&mut self, edition: Edition, meta: RemoteMeta) {
+        self.editions.insert(edition.id().to_string(), meta);
+    }
+}
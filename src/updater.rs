@@ -0,0 +1,137 @@
+use std::time::Duration;
+
+use crate::config::GeoliteConfig;
+use crate::db;
+
+/// Keeps the database fresh from inside a long running process. It calls
+/// `db::update_db`, which only rebuilds once the current database is due, then
+/// sleeps until the next due time (at most `check_every`) plus up to `jitter`.
+/// Failures are logged and retried after `check_every`, doubling the wait
+/// with every further failure in a row up to `max_backoff`, so that a
+/// release that keeps failing does not use up the download quota.
+pub struct Updater {
+    config: GeoliteConfig,
+    check_every: Duration,
+    jitter: Duration,
+    max_backoff: Duration,
+}
+
+impl Updater {
+    pub fn new(config: GeoliteConfig) -> Updater {
+        Updater {
+            config,
+            check_every: Duration::from_secs(3600),
+            jitter: Duration::from_secs(300),
+            max_backoff: Duration::from_secs(3600 * 24),
+        }
+    }
+
+    pub fn check_every(mut self, d: Duration) -> Updater {
+        self.check_every = d;
+        self
+    }
+
+    pub fn jitter(mut self, d: Duration) -> Updater {
+        self.jitter = d;
+        self
+    }
+
+    pub fn max_backoff(mut self, d: Duration) -> Updater {
+        self.max_backoff = d;
+        self
+    }
+
+    /// Runs forever.
+    pub async fn run(self) {
+        let mut failures = 0;
+        loop {
+            let wait = match db::update_db(&self.config).await {
+                Ok(_) => {
+                    failures = 0;
+                    self.until_due().min(self.check_every)
+                }
+                Err(e) => {
+                    failures += 1;
+                    let wait = self.backoff(failures);
+                    eprintln!("update failed, retrying in {:?}: {:?}", wait, e);
+                    wait
+                }
+            };
+            tokio::time::sleep(wait + random_upto(self.jitter)).await;
+        }
+    }
+
+    // `check_every` after the first failure in a row, doubled for each one
+    // after it.
+    fn backoff(&self, failures: u32) -> Duration {
+        self.check_every
+            .checked_mul(1 << (failures - 1).min(16))
+            .unwrap_or(Duration::MAX)
+            .min(self.max_backoff.max(self.check_every))
+    }
+
+    fn until_due(&self) -> Duration {
+        db::next_update(&self.config)
+            .duration_since(std::time::SystemTime::now())
+            .unwrap_or(Duration::ZERO)
+    }
+
+    pub fn spawn(self) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+}
+
+// Spreads wake ups of several instances apart; it need not be a good RNG.
+fn random_upto(max: Duration) -> Duration {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(0);
+    match max.as_millis() as u64 {
+        0 => Duration::ZERO,
+        ms => Duration::from_millis(nanos.wrapping_mul(2654435761) % ms),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn updater(check_every: u64, max_backoff: u64) -> Updater {
+        let config = GeoliteConfig::builder()
+            .db_dir("/var/lib/geolite")
+            .build()
+            .unwrap();
+        Updater::new(config)
+            .check_every(Duration::from_secs(check_every))
+            .max_backoff(Duration::from_secs(max_backoff))
+    }
+
+    #[test]
+    fn backoffs() {
+        let cases = [
+            // (check_every, max_backoff, failures, wait)
+            (60, 3600, 1, 60),
+            (60, 3600, 2, 120),
+            (60, 3600, 3, 240),
+            (60, 3600, 6, 1920),
+            (60, 3600, 7, 3600),
+            (60, 3600, 1000, 3600),
+            (1, u64::MAX, 17, 1 << 16),
+            (1, u64::MAX, u32::MAX as u64, 1 << 16),
+            (u64::MAX / 2, u64::MAX, 3, u64::MAX),
+            (3600, 60, 1, 3600),
+            (3600, 60, 5, 3600),
+        ];
+        for (check_every, max_backoff, failures, wait) in cases {
+            assert_eq!(
+                updater(check_every, max_backoff).backoff(failures as u32),
+                Duration::from_secs(wait),
+                "check_every {}, max_backoff {}, failures {}",
+                check_every,
+                max_backoff,
+                failures
+            );
+        }
+    }
+}